@@ -0,0 +1,62 @@
+//! Encoding of small INSERTs, the bulk of a high-QPS write workload.
+//!
+//! Run with `cargo bench --bench write_path`.
+
+#[macro_use]
+extern crate criterion;
+extern crate rustcql;
+
+use criterion::{Criterion, Throughput, black_box};
+
+use rustcql::Statement;
+use rustcql::shared::{Consistency, ProtocolVersion, Request};
+use rustcql::writing::{FrameEncoder, WriteMessage};
+
+/// Statements per iteration.
+const BATCH: usize = 1000;
+
+fn insert(i: usize) -> Statement {
+    Statement::query("INSERT INTO ks.events (id, kind, payload) VALUES (?, ?, ?)")
+        .values((i as i64, "click".to_string(), vec![0u8; 32]))
+        .consistency(Consistency::One)
+}
+
+fn write_path(c: &mut Criterion) {
+    let statements: Vec<Statement> = (0..BATCH).map(insert).collect();
+
+    let mut group = c.benchmark_group("small_inserts");
+    group.throughput(Throughput::Elements(BATCH as u64));
+
+    // a fresh buffer per frame
+    group.bench_function("write_message", |b| {
+        let mut out = Vec::with_capacity(1 << 20);
+        b.iter(|| {
+            out.clear();
+            for statement in statements.iter() {
+                let request = Request::Statement(statement.clone());
+                out.write_message_with_version(request, ProtocolVersion::V4).unwrap();
+            }
+            black_box(out.len())
+        })
+    });
+
+    // the buffer is reused, as on a connection
+    group.bench_function("frame_encoder", |b| {
+        let mut encoder = FrameEncoder::new();
+        let mut out = Vec::with_capacity(1 << 20);
+        b.iter(|| {
+            out.clear();
+            for statement in statements.iter() {
+                let request = Request::Statement(statement.clone());
+                let frame = encoder.encode(&request, ProtocolVersion::V4, 1).unwrap();
+                out.extend_from_slice(frame);
+            }
+            black_box(out.len())
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, write_path);
+criterion_main!(benches);
@@ -0,0 +1,260 @@
+//! Derive macros for mapping Rust structs to the `Row`/`Column` model of rustcql.
+//!
+//! Fields can be renamed with `#[cql(rename = "column_name")]`. Fields of type
+//! `Option<T>` and fields marked `#[cql(default)]` may be missing from the row
+//! (or UDT value) and are then filled with `None`/`Default::default()`.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+
+use syn::{
+	Data,
+	DeriveInput,
+	Fields,
+	Ident,
+	Index,
+	LitStr,
+	Type
+};
+
+struct FieldAttrs {
+	name: String,
+	optional: bool
+}
+
+struct Field {
+	member: TokenStream2,
+	ty: Type,
+	attrs: FieldAttrs
+}
+
+#[proc_macro_derive(FromRow, attributes(cql))]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+	expand_from_row(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+#[proc_macro_derive(IntoValues, attributes(cql))]
+pub fn derive_into_values(input: TokenStream) -> TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+	expand_into_values(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+#[proc_macro_derive(Udt, attributes(cql))]
+pub fn derive_udt(input: TokenStream) -> TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+	expand_udt(&input).unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn expand_from_row(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let ident = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+	let (fields, named) = struct_fields(input)?;
+
+	let values = fields.iter().enumerate().map(|(index, field)| {
+		let ty = &field.ty;
+		let lookup = if named {
+			let name = &field.attrs.name;
+			quote!(row.index_of(#name))
+		} else {
+			quote!(if #index < row.len() { Some(#index) } else { None })
+		};
+		let missing = if field.attrs.optional {
+			quote!(::std::default::Default::default())
+		} else {
+			let name = if named { field.attrs.name.clone() } else { index.to_string() };
+			quote!(return Err(::rustcql::MappingError::MissingColumn(#name.to_string())))
+		};
+		quote! {
+			match #lookup {
+				Some(index) => row.get_as::<#ty>(index)?,
+				None => #missing
+			}
+		}
+	});
+
+	let body = construct(ident, named, &fields, values.collect());
+
+	Ok(quote! {
+		impl #impl_generics ::rustcql::FromRow for #ident #ty_generics #where_clause {
+			fn from_row(row: &::rustcql::shared::Row) -> ::std::result::Result<Self, ::rustcql::MappingError> {
+				Ok(#body)
+			}
+		}
+	})
+}
+
+fn expand_into_values(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let ident = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+	let (fields, named) = struct_fields(input)?;
+
+	let values = fields.iter().map(|field| {
+		let member = &field.member;
+		quote!(::rustcql::ToCql::to_cql(&self.#member))
+	});
+
+	let positional = quote! {
+		impl #impl_generics ::rustcql::IntoValues for #ident #ty_generics #where_clause {
+			fn into_values(self) -> Vec<::rustcql::shared::Column> {
+				vec![#(#values),*]
+			}
+		}
+	};
+
+	if !named {
+		return Ok(positional);
+	}
+
+	let named_values = fields.iter().map(|field| {
+		let member = &field.member;
+		let name = &field.attrs.name;
+		quote!((#name.to_string(), ::rustcql::ToCql::to_cql(&self.#member)))
+	});
+
+	Ok(quote! {
+		#positional
+
+		impl #impl_generics ::rustcql::IntoNamedValues for #ident #ty_generics #where_clause {
+			fn into_named_values(self) -> Vec<(String, ::rustcql::shared::Column)> {
+				vec![#(#named_values),*]
+			}
+		}
+	})
+}
+
+fn expand_udt(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let ident = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+	let (fields, named) = struct_fields(input)?;
+
+	if !named {
+		return Err(syn::Error::new_spanned(ident, "#[derive(Udt)] requires a struct with named fields"));
+	}
+
+	let values = fields.iter().map(|field| {
+		let ty = &field.ty;
+		let name = &field.attrs.name;
+		let missing = if field.attrs.optional {
+			quote!(::std::default::Default::default())
+		} else {
+			quote!(return Err(::rustcql::MappingError::MissingColumn(#name.to_string())))
+		};
+		quote! {
+			match ::rustcql::mapping::udt_field(fields, #name) {
+				Some(column) => <#ty as ::rustcql::FromCql>::from_cql(column).map_err(|e| e.in_column(#name))?,
+				None => #missing
+			}
+		}
+	});
+
+	let body = construct(ident, named, &fields, values.collect());
+
+	// fields are written in declaration order, which must match the type definition
+	let to_fields = fields.iter().map(|field| {
+		let member = &field.member;
+		let name = &field.attrs.name;
+		quote!((#name.to_string(), ::rustcql::ToCql::to_cql(&self.#member)))
+	});
+
+	Ok(quote! {
+		impl #impl_generics ::rustcql::FromCql for #ident #ty_generics #where_clause {
+			fn from_cql(column: &::rustcql::shared::Column) -> ::std::result::Result<Self, ::rustcql::MappingError> {
+				match *column {
+					::rustcql::shared::Column::Udt(ref fields) => Ok(#body),
+					_ => Err(::rustcql::MappingError::mismatch("udt", column))
+				}
+			}
+		}
+
+		impl #impl_generics ::rustcql::ToCql for #ident #ty_generics #where_clause {
+			fn to_cql(&self) -> ::rustcql::shared::Column {
+				::rustcql::shared::Column::Udt(vec![#(#to_fields),*])
+			}
+		}
+	})
+}
+
+fn construct(ident: &Ident, named: bool, fields: &[Field], values: Vec<TokenStream2>) -> TokenStream2 {
+	if named {
+		let members = fields.iter().map(|field| &field.member);
+		quote!(#ident { #(#members: #values),* })
+	} else {
+		quote!(#ident ( #(#values),* ))
+	}
+}
+
+fn struct_fields(input: &DeriveInput) -> syn::Result<(Vec<Field>, bool)> {
+	let data = match input.data {
+		Data::Struct(ref data) => data,
+		_ => return Err(syn::Error::new_spanned(&input.ident, "rustcql derives only support structs"))
+	};
+
+	let (fields, named) = match data.fields {
+		Fields::Named(ref fields) => (fields.named.iter().collect::<Vec<_>>(), true),
+		Fields::Unnamed(ref fields) => (fields.unnamed.iter().collect::<Vec<_>>(), false),
+		Fields::Unit => (vec!(), true)
+	};
+
+	let mut result = vec!();
+	for (index, field) in fields.into_iter().enumerate() {
+		let member = match field.ident {
+			Some(ref ident) => quote!(#ident),
+			None => {
+				let index = Index::from(index);
+				quote!(#index)
+			}
+		};
+		let default_name = match field.ident {
+			Some(ref ident) => ident.to_string().trim_start_matches("r#").to_string(),
+			None => index.to_string()
+		};
+		result.push(Field {
+			member,
+			ty: field.ty.clone(),
+			attrs: field_attrs(field, default_name)?
+		});
+	}
+	Ok((result, named))
+}
+
+fn field_attrs(field: &syn::Field, default_name: String) -> syn::Result<FieldAttrs> {
+	let mut attrs = FieldAttrs {
+		name: default_name,
+		optional: is_option(&field.ty)
+	};
+	for attr in field.attrs.iter() {
+		if !attr.path().is_ident("cql") {
+			continue;
+		}
+		attr.parse_nested_meta(|meta| {
+			if meta.path.is_ident("rename") {
+				let name: LitStr = meta.value()?.parse()?;
+				attrs.name = name.value();
+				Ok(())
+			} else if meta.path.is_ident("default") {
+				attrs.optional = true;
+				Ok(())
+			} else {
+				Err(meta.error("unsupported cql attribute, expected `rename = \"...\"` or `default`"))
+			}
+		})?;
+	}
+	Ok(attrs)
+}
+
+fn is_option(ty: &Type) -> bool {
+	match *ty {
+		Type::Path(ref path) if path.qself.is_none() => match path.path.segments.last() {
+			Some(segment) => segment.ident == "Option",
+			None => false
+		},
+		_ => false
+	}
+}
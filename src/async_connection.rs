@@ -0,0 +1,316 @@
+//! A connection on the tokio-core reactor, for paging without blocking.
+//!
+//! ```ignore
+//! let mut core = Core::new().unwrap();
+//! let conn = core.run(connect_async(&addr, &core.handle())).unwrap();
+//! let statement = Statement::query("SELECT * FROM testing.users").page_size(100);
+//! let rows = core.run(conn.rows(statement).collect()).unwrap();
+//! ```
+//!
+//! It speaks protocol v4 without compression and runs one request at a time.
+
+use std::io::{
+    Result,
+    Error,
+    ErrorKind,
+    Read,
+    Write
+};
+
+use std::net::SocketAddr;
+use std::vec;
+
+use futures::{
+    Async,
+    Future,
+    Poll,
+    Stream
+};
+
+use tokio_core::net::{
+    TcpStream,
+    TcpStreamNew
+};
+use tokio_core::reactor::Handle;
+
+use shared::{
+    ProtocolVersion,
+    Compression,
+    Request,
+    Response,
+    Envelope,
+    Row
+};
+
+use statement::Statement;
+
+use paging::into_page;
+
+use reading::reader::{
+    read_frame,
+    decode_envelope
+};
+
+use writing::FrameEncoder;
+
+use connection::startup_request;
+
+// the one stream id requests are sent on
+const STREAM: i16 = 0;
+
+/// A connection whose requests are driven by the reactor instead of blocking.
+pub struct AsyncConnection {
+    stream: TcpStream,
+    encoder: FrameEncoder,
+    // request being written, and how much of it was
+    output: Vec<u8>,
+    written: usize,
+    // bytes read and not decoded yet
+    input: Vec<u8>
+}
+
+/// Connects to `addr` and starts up with protocol v4.
+pub fn connect_async(addr: &SocketAddr, handle: &Handle) -> Startup {
+    Startup {
+        connecting: Some(TcpStream::connect(addr, handle)),
+        conn: None
+    }
+}
+
+impl AsyncConnection {
+    fn new(stream: TcpStream) -> AsyncConnection {
+        AsyncConnection {
+            stream: stream,
+            encoder: FrameEncoder::new(),
+            output: vec!(),
+            written: 0,
+            input: vec!()
+        }
+    }
+
+    /// The rows of `statement`, requesting each page once the previous one is used up.
+    pub fn rows(self, statement: Statement) -> RowStream {
+        RowStream {
+            conn: self,
+            statement: statement,
+            rows: Vec::new().into_iter(),
+            pending: false,
+            exhausted: false,
+            warnings: vec!()
+        }
+    }
+
+    fn start(&mut self, request: &Request) -> Result<()> {
+        let frame = try!(self.encoder.encode(request, ProtocolVersion::V4, STREAM));
+        self.output.clear();
+        self.output.extend_from_slice(frame);
+        self.written = 0;
+        Ok(())
+    }
+
+    // Writes what is left of the request, then reads until its response is complete.
+    fn poll_response(&mut self) -> Poll<Envelope, Error> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "Connection closed while writing")),
+                Ok(n) => self.written += n,
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e)
+            }
+        }
+        loop {
+            if let Some(len) = frame_len(&self.input) {
+                if self.input.len() >= len {
+                    let frame = try!(read_frame(&mut &self.input[..len]));
+                    self.input.drain(..len);
+                    if frame.stream != STREAM {
+                        // an event, nothing else is sent on other streams
+                        continue;
+                    }
+                    return decode_envelope(frame, None).map(Async::Ready);
+                }
+            }
+            let mut chunk = [0u8; 8192];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed while reading")),
+                Ok(n) => self.input.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+// Length of the frame at the start of `input` with its header, once the header is in.
+fn frame_len(input: &[u8]) -> Option<usize> {
+    if input.len() < 9 {
+        return None;
+    }
+    let len = ((input[5] as usize) << 24) | ((input[6] as usize) << 16)
+        | ((input[7] as usize) << 8) | input[8] as usize;
+    Some(9 + len)
+}
+
+/// Resolves to the connection once the server answered STARTUP.
+pub struct Startup {
+    connecting: Option<TcpStreamNew>,
+    conn: Option<AsyncConnection>
+}
+
+impl Future for Startup {
+    type Item = AsyncConnection;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<AsyncConnection, Error> {
+        if let Some(mut connecting) = self.connecting.take() {
+            match try!(connecting.poll()) {
+                Async::Ready(stream) => {
+                    let mut conn = AsyncConnection::new(stream);
+                    try!(conn.start(&startup_request(ProtocolVersion::V4, Compression::None)));
+                    self.conn = Some(conn);
+                }
+                Async::NotReady => {
+                    self.connecting = Some(connecting);
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+        let envelope = match self.conn {
+            Some(ref mut conn) => match try!(conn.poll_response()) {
+                Async::Ready(envelope) => envelope,
+                Async::NotReady => return Ok(Async::NotReady)
+            },
+            None => return Err(Error::new(ErrorKind::Other, "Startup polled after it completed"))
+        };
+        match envelope.response {
+            Response::Ready => Ok(Async::Ready(self.conn.take().unwrap())),
+            response => Err(Error::new(ErrorKind::ConnectionRefused, format!("Invalid response after startup: {:?}", response)))
+        }
+    }
+}
+
+/// Yields the rows of a paged statement without blocking the reactor.
+pub struct RowStream {
+    conn: AsyncConnection,
+    statement: Statement,
+    rows: vec::IntoIter<Row>,
+    // whether the next page was requested
+    pending: bool,
+    exhausted: bool,
+    warnings: Vec<String>
+}
+
+impl RowStream {
+    /// Warnings the server sent with the pages read so far.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Gives the connection back, `None` while a page is still being read.
+    pub fn into_connection(self) -> Option<AsyncConnection> {
+        if self.pending { None } else { Some(self.conn) }
+    }
+}
+
+impl Stream for RowStream {
+    type Item = Row;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Row>, Error> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Ok(Async::Ready(Some(row)));
+            }
+            if self.exhausted {
+                return Ok(Async::Ready(None));
+            }
+            if !self.pending {
+                try!(self.conn.start(&Request::Statement(self.statement.clone())));
+                self.pending = true;
+            }
+            let envelope = match self.conn.poll_response() {
+                Ok(Async::Ready(envelope)) => envelope,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    self.exhausted = true;
+                    return Err(e);
+                }
+            };
+            self.pending = false;
+            self.warnings.extend(envelope.warnings);
+            match into_page(envelope.response) {
+                Ok((rows, paging_state)) => {
+                    self.rows = rows.into_iter();
+                    self.exhausted = paging_state.is_none();
+                    self.statement.paging_state = paging_state;
+                }
+                Err(e) => {
+                    self.exhausted = true;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    use futures::Stream;
+    use tokio_core::reactor::Core;
+
+    use shared::Column;
+
+    use statement::Statement;
+
+    use super::*;
+
+    // A rows result with one int column `v` and one row.
+    fn page(value: i32, paging_state: Option<u8>) -> Vec<u8> {
+        let mut body = vec!(0, 0, 0, 2);
+        body.extend_from_slice(&[0, 0, 0, if paging_state.is_some() { 0x03 } else { 0x01 }, 0, 0, 0, 1]);
+        if let Some(state) = paging_state {
+            body.extend_from_slice(&[0, 0, 0, 1, state]);
+        }
+        body.extend_from_slice(&[0, 2, b'k', b's', 0, 1, b't', 0, 1, b'v', 0, 9]);
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 4]);
+        body.extend_from_slice(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]);
+        body
+    }
+
+    #[test]
+    fn test_row_stream() {
+        // READY to STARTUP, then two pages
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let bodies = vec!((0x02, vec!()), (0x08, page(1, Some(7))), (0x08, page(2, None)));
+            for (opcode, body) in bodies {
+                let mut header = [0u8; 9];
+                stream.read_exact(&mut header).unwrap();
+                let mut request = vec![0; frame_len(&header).unwrap() - 9];
+                stream.read_exact(&mut request).unwrap();
+
+                let len = body.len();
+                let response = [0x84, 0, header[2], header[3], opcode,
+                    (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+                stream.write_all(&response).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        let mut core = Core::new().unwrap();
+        let conn = core.run(connect_async(&addr, &core.handle())).unwrap();
+        let rows = core.run(conn.rows(Statement::query("SELECT v FROM ks.t").page_size(1)).collect()).unwrap();
+
+        let values: Vec<&Column> = rows.iter().map(|row| &row.columns[0]).collect();
+        assert_eq!(values, vec!(&Column::Int(1), &Column::Int(2)));
+    }
+}
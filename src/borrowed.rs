@@ -0,0 +1,414 @@
+//! Zero-copy, lazily decoded result pages.
+//!
+//! A `RowsPage` keeps the body of the response frame and the offset of every
+//! value in it. Rows hand out `&str` and `&[u8]` views into that buffer, so
+//! scanning a page allocates nothing per value, and a value is only decoded
+//! into a `Column` when it is asked for.
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::str;
+use std::result;
+use std::sync::Arc;
+
+use byteorder::{
+    BigEndian,
+    ByteOrder
+};
+
+use shared::{
+    Row,
+    Column,
+    ColumnSpec,
+    ColumnType
+};
+
+use uuid::Uuid;
+
+use mapping::{
+    FromCql,
+    MappingError
+};
+
+use reading::value::read_column_value;
+
+pub struct RowsPage {
+    pub(crate) body: Vec<u8>,
+    pub(crate) specs: Arc<Vec<ColumnSpec>>,
+    // start and length of every value, row by row, a negative length is a null
+    pub(crate) cells: Vec<(usize, i32)>,
+    pub(crate) row_count: usize,
+    pub(crate) paging_state: Option<Vec<u8>>,
+    // set when the result metadata of the prepared statement changed (v5)
+    pub(crate) new_metadata_id: Option<Vec<u8>>,
+    pub(crate) tracing_id: Option<Uuid>,
+    pub(crate) warnings: Vec<String>,
+    pub(crate) custom_payload: HashMap<String, Vec<u8>>
+}
+
+impl RowsPage {
+    pub(crate) fn empty() -> RowsPage {
+        RowsPage {
+            body: vec!(),
+            specs: Arc::new(vec!()),
+            cells: vec!(),
+            row_count: 0,
+            paging_state: None,
+            new_metadata_id: None,
+            tracing_id: None,
+            warnings: vec!(),
+            custom_payload: HashMap::new()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.row_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.row_count == 0
+    }
+
+    pub fn specs(&self) -> &Arc<Vec<ColumnSpec>> {
+        &self.specs
+    }
+
+    /// Paging state of the next page, `None` on the last one.
+    pub fn paging_state(&self) -> Option<&Vec<u8>> {
+        self.paging_state.as_ref()
+    }
+
+    /// Set when the page was requested with tracing, see `Connection::trace`.
+    pub fn tracing_id(&self) -> Option<Uuid> {
+        self.tracing_id
+    }
+
+    /// Warnings the server sent with the page.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Custom payload set by a server-side query handler.
+    pub fn custom_payload(&self) -> &HashMap<String, Vec<u8>> {
+        &self.custom_payload
+    }
+
+    pub fn row(&self, index: usize) -> Option<RowRef<'_>> {
+        if index < self.row_count {
+            Some(RowRef { page: self, index: index })
+        } else {
+            None
+        }
+    }
+
+    pub fn rows(&self) -> RowRefs<'_> {
+        RowRefs { page: self, index: 0 }
+    }
+}
+
+pub struct RowRefs<'a> {
+    page: &'a RowsPage,
+    index: usize
+}
+
+impl<'a> Iterator for RowRefs<'a> {
+    type Item = RowRef<'a>;
+
+    fn next(&mut self) -> Option<RowRef<'a>> {
+        let row = self.page.row(self.index);
+        if row.is_some() {
+            self.index += 1;
+        }
+        row
+    }
+}
+
+/// A row of a `RowsPage`, borrowing its values from the page.
+#[derive(Clone, Copy)]
+pub struct RowRef<'a> {
+    page: &'a RowsPage,
+    index: usize
+}
+
+impl<'a> RowRef<'a> {
+    pub fn len(&self) -> usize {
+        self.page.specs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.page.specs.is_empty()
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.page.specs.iter().position(|spec| spec.name == name)
+    }
+
+    /// The encoded bytes of a column, `None` when it is null or out of range.
+    pub fn raw(&self, column: usize) -> Option<&'a [u8]> {
+        if column >= self.len() {
+            return None;
+        }
+        let (start, len) = self.page.cells[self.index * self.len() + column];
+        if len < 0 {
+            None
+        } else {
+            Some(&self.page.body[start..start + len as usize])
+        }
+    }
+
+    /// Converts the column at `index` without copying it.
+    pub fn get<T: FromCqlRef<'a>>(&self, index: usize) -> result::Result<T, MappingError> {
+        match self.page.specs.get(index) {
+            Some(spec) => T::from_cql_ref(spec.data_type, self.raw(index)).map_err(|e| e.in_column(&spec.name)),
+            None => Err(MappingError::MissingColumn(index.to_string()))
+        }
+    }
+
+    pub fn get_by_name<T: FromCqlRef<'a>>(&self, name: &str) -> result::Result<T, MappingError> {
+        match self.index_of(name) {
+            Some(index) => self.get(index),
+            None => Err(MappingError::MissingColumn(name.to_string()))
+        }
+    }
+
+    /// Decodes only the column at `index`.
+    pub fn column(&self, index: usize) -> result::Result<Column, MappingError> {
+        let spec = match self.page.specs.get(index) {
+            Some(spec) => spec,
+            None => return Err(MappingError::MissingColumn(index.to_string()))
+        };
+        let (start, len) = self.page.cells[self.index * self.len() + index];
+        if len < 0 {
+            return Ok(Column::None);
+        }
+        let bytes = &self.page.body[start..start + len as usize];
+        try!(check_value(spec.data_type, bytes).map_err(|e| MappingError::Message(format!("column \"{}\": {}", spec.name, e))));
+        // the value's length sits right in front of it, the cursor ends with the value
+        let mut cursor = Cursor::new(&self.page.body[start - 4..start + len as usize]);
+        Ok(read_column_value(&mut cursor, spec.data_type, &spec.collection_spec))
+    }
+
+    /// Decodes only the column at `index` and converts it, for types that can't borrow.
+    pub fn get_as<T: FromCql>(&self, index: usize) -> result::Result<T, MappingError> {
+        let column = try!(self.column(index));
+        T::from_cql(&column).map_err(|e| e.in_column(&self.page.specs[index].name))
+    }
+
+    pub fn get_by_name_as<T: FromCql>(&self, name: &str) -> result::Result<T, MappingError> {
+        match self.index_of(name) {
+            Some(index) => self.get_as(index),
+            None => Err(MappingError::MissingColumn(name.to_string()))
+        }
+    }
+
+    /// Decodes the whole row into an owned `Row`.
+    pub fn to_row(&self) -> result::Result<Row, MappingError> {
+        let mut columns = Vec::with_capacity(self.len());
+        for index in 0..self.len() {
+            columns.push(try!(self.column(index)));
+        }
+        Ok(Row {
+            specs: self.page.specs.clone(),
+            columns: columns
+        })
+    }
+}
+
+// Fails on values `read_column_value` would misread: fixed-width ones of another
+// width and text that isn't UTF-8.
+fn check_value(data_type: ColumnType, bytes: &[u8]) -> result::Result<(), String> {
+    let width = match data_type {
+        ColumnType::Tinyint | ColumnType::Boolean => 1,
+        ColumnType::Smallint => 2,
+        ColumnType::Int | ColumnType::Float => 4,
+        ColumnType::Bigint | ColumnType::Double | ColumnType::Timestamp => 8,
+        ColumnType::Uuid | ColumnType::Timeuuid => 16,
+        ColumnType::Blob | ColumnType::Inet | ColumnType::Set | ColumnType::List
+            | ColumnType::Map | ColumnType::Tuple | ColumnType::UDT => return Ok(()),
+        _ => return str::from_utf8(bytes).map(|_| ()).map_err(|e| e.to_string())
+    };
+    if bytes.len() != width {
+        return Err(format!("expected {} bytes for {:?}, found {}", width, data_type, bytes.len()));
+    }
+    Ok(())
+}
+
+/// Conversion from the encoded bytes of a value, which may borrow from them.
+pub trait FromCqlRef<'a>: Sized {
+    /// `bytes` is `None` for a null.
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<Self, MappingError>;
+}
+
+fn type_name(data_type: ColumnType) -> &'static str {
+    match data_type {
+        ColumnType::Ascii | ColumnType::Text | ColumnType::Varchar => "string",
+        ColumnType::Boolean => "boolean",
+        ColumnType::Blob | ColumnType::Inet => "blob",
+        ColumnType::Int => "int",
+        ColumnType::Bigint | ColumnType::Counter => "bigint",
+        ColumnType::Float => "float",
+        ColumnType::Double => "double",
+        ColumnType::Timestamp => "timestamp",
+        ColumnType::Set => "set",
+        ColumnType::List => "list",
+        ColumnType::Map => "map",
+        ColumnType::Tuple => "tuple",
+        ColumnType::UDT => "udt",
+        _ => "other"
+    }
+}
+
+fn not_null(bytes: Option<&[u8]>) -> result::Result<&[u8], MappingError> {
+    bytes.ok_or(MappingError::UnexpectedNull(None))
+}
+
+fn fixed<'a>(expected: &'static str, data_type: ColumnType, bytes: Option<&'a [u8]>, len: usize) -> result::Result<&'a [u8], MappingError> {
+    let bytes = try!(not_null(bytes));
+    if bytes.len() != len || type_name(data_type) != expected {
+        return Err(MappingError::TypeMismatch { column: None, expected: expected, found: type_name(data_type) });
+    }
+    Ok(bytes)
+}
+
+impl<'a, T: FromCqlRef<'a>> FromCqlRef<'a> for Option<T> {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<Option<T>, MappingError> {
+        match bytes {
+            Some(_) => T::from_cql_ref(data_type, bytes).map(Some),
+            None => Ok(None)
+        }
+    }
+}
+
+impl<'a> FromCqlRef<'a> for &'a str {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<&'a str, MappingError> {
+        let bytes = try!(not_null(bytes));
+        if type_name(data_type) != "string" {
+            return Err(MappingError::TypeMismatch { column: None, expected: "string", found: type_name(data_type) });
+        }
+        str::from_utf8(bytes).map_err(|e| MappingError::Message(e.to_string()))
+    }
+}
+
+/// The raw bytes of any column type.
+impl<'a> FromCqlRef<'a> for &'a [u8] {
+    fn from_cql_ref(_: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<&'a [u8], MappingError> {
+        not_null(bytes)
+    }
+}
+
+impl<'a> FromCqlRef<'a> for bool {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<bool, MappingError> {
+        fixed("boolean", data_type, bytes, 1).map(|bytes| bytes[0] != 0)
+    }
+}
+
+impl<'a> FromCqlRef<'a> for i32 {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<i32, MappingError> {
+        fixed("int", data_type, bytes, 4).map(BigEndian::read_i32)
+    }
+}
+
+impl<'a> FromCqlRef<'a> for i64 {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<i64, MappingError> {
+        let expected = match data_type {
+            ColumnType::Timestamp => "timestamp",
+            _ => "bigint"
+        };
+        fixed(expected, data_type, bytes, 8).map(BigEndian::read_i64)
+    }
+}
+
+impl<'a> FromCqlRef<'a> for f32 {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<f32, MappingError> {
+        fixed("float", data_type, bytes, 4).map(BigEndian::read_f32)
+    }
+}
+
+impl<'a> FromCqlRef<'a> for f64 {
+    fn from_cql_ref(data_type: ColumnType, bytes: Option<&'a [u8]>) -> result::Result<f64, MappingError> {
+        fixed("double", data_type, bytes, 8).map(BigEndian::read_f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reading::reader::{
+        read_frame,
+        decode_rows_page
+    };
+
+    use super::*;
+
+    fn page() -> RowsPage {
+        let mut body = vec![0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2];   // rows, global table spec, 2 columns
+        body.extend_from_slice(&[0, 1, b'k', 0, 1, b't']);
+        body.extend_from_slice(&[0, 4, b'n', b'a', b'm', b'e', 0, 0x0D]);
+        body.extend_from_slice(&[0, 3, b'a', b'g', b'e', 0, 0x09]);
+        body.extend_from_slice(&[0, 0, 0, 2]);
+        body.extend_from_slice(&[0, 0, 0, 2, b'a', b'b', 0, 0, 0, 4, 0, 0, 0, 42]);
+        body.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+
+        let mut frame = vec![0x84, 0, 0, 1, 0x08, 0, 0, 0, body.len() as u8];
+        frame.extend_from_slice(&body);
+        let frame = read_frame(&mut &frame[..]).unwrap();
+        decode_rows_page(frame, None).unwrap()
+    }
+
+    #[test]
+    fn test_borrowed_values() {
+        let page = page();
+        assert_eq!(page.len(), 2);
+        assert!(page.paging_state().is_none());
+
+        let first = page.row(0).unwrap();
+        let name: &str = first.get(0).unwrap();
+        assert_eq!(name, "ab");
+        assert_eq!(first.get_by_name::<i32>("age").unwrap(), 42);
+        assert_eq!(first.raw(1), Some(&[0, 0, 0, 42][..]));
+        assert!(first.get::<i64>(1).is_err());
+
+        let second = page.row(1).unwrap();
+        assert_eq!(second.get::<Option<&str>>(0).unwrap(), None);
+        assert_eq!(second.get::<i32>(1).unwrap_err(), MappingError::UnexpectedNull(Some("age".to_string())));
+
+        assert_eq!(page.rows().count(), 2);
+    }
+
+    #[test]
+    fn test_to_row() {
+        let page = page();
+        let row = page.row(0).unwrap().to_row().unwrap();
+        assert_eq!(row.columns, vec![Column::String("ab".to_string()), Column::Int(42)]);
+        assert_eq!(page.row(1).unwrap().to_row().unwrap().columns, vec![Column::None, Column::None]);
+    }
+
+    #[test]
+    fn test_lazy_columns() {
+        let page = page();
+        let first = page.row(0).unwrap();
+        assert_eq!(first.column(1), Ok(Column::Int(42)));
+        assert_eq!(first.column(2), Err(MappingError::MissingColumn("2".to_string())));
+        assert_eq!(first.get_by_name_as::<String>("name").unwrap(), "ab".to_string());
+        assert_eq!(page.row(1).unwrap().get_as::<Option<i32>>(1).unwrap(), None);
+
+        let adults = page.rows().filter(|row| row.get::<Option<i32>>(1).unwrap().unwrap_or(0) >= 18).count();
+        assert_eq!(adults, 1);
+    }
+
+    #[test]
+    fn test_short_values() {
+        // an empty int followed by a full one, which must not be read in its place
+        let mut body = vec![0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1];
+        body.extend_from_slice(&[0, 1, b'k', 0, 1, b't', 0, 1, b'v', 0, 0x09]);
+        body.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 7]);
+        let mut frame = vec![0x84, 0, 0, 1, 0x08, 0, 0, 0, body.len() as u8];
+        frame.extend_from_slice(&body);
+        let page = decode_rows_page(read_frame(&mut &frame[..]).unwrap(), None).unwrap();
+
+        let empty = page.row(0).unwrap();
+        assert_eq!(empty.column(0), Err(MappingError::Message("column \"v\": expected 4 bytes for Int, found 0".to_string())));
+        assert!(empty.get_as::<i32>(0).is_err());
+        assert!(empty.to_row().is_err());
+        assert_eq!(page.row(1).unwrap().column(0), Ok(Column::Int(7)));
+    }
+}
@@ -0,0 +1,32 @@
+//! Checksums of protocol v5 segments.
+
+use crc32fast::Hasher;
+
+static CRC24_INIT: u32 = 0x875060;
+static CRC24_POLY: u32 = 0x1974F0B;
+
+/// Bytes every payload checksum starts from, so an all-zero payload doesn't check out.
+static CRC32_INITIAL_BYTES: [u8; 4] = [0xFA, 0x2D, 0x55, 0xCA];
+
+/// CRC24 of a segment header.
+pub fn crc24(bytes: &[u8]) -> u32 {
+    let mut crc = CRC24_INIT;
+    for byte in bytes.iter() {
+        crc ^= (*byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= CRC24_POLY;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+/// CRC32 of a segment payload.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(&CRC32_INITIAL_BYTES);
+    hasher.update(bytes);
+    hasher.finalize()
+}
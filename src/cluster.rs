@@ -0,0 +1,342 @@
+//! Sessions over every node of a cluster.
+//!
+//! `Cluster::connect` tries the contact points in turn until one answers,
+//! reads `system.local` and `system.peers` from it and opens a `Pool` to each
+//! node found, all at once. Nodes are discovered once, when connecting.
+
+use std::io::{
+    Result,
+    Error,
+    ErrorKind
+};
+
+use std::net::{
+    IpAddr,
+    SocketAddr,
+    ToSocketAddrs
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering
+};
+use std::thread;
+
+use uuid::Uuid;
+
+use connection::{
+    Connection,
+    connect_with_timeout
+};
+
+use shared::{
+    Consistency,
+    Response,
+    Row
+};
+
+use statement::Statement;
+
+use paging::into_page;
+
+use pool::{
+    Pool,
+    PoolConfig,
+    PooledConnection
+};
+
+static LOCAL_QUERY: &'static str =
+    "SELECT data_center, rack, tokens, release_version, host_id FROM system.local";
+
+static PEERS_QUERY: &'static str =
+    "SELECT peer, rpc_address, data_center, rack, tokens, release_version, host_id FROM system.peers";
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub address: SocketAddr,
+    pub datacenter: Option<String>,
+    pub rack: Option<String>,
+    pub tokens: Vec<String>,
+    pub release_version: Option<String>,
+    pub host_id: Option<Uuid>
+}
+
+/// Contact points to discover a cluster from.
+pub struct Cluster {
+    contact_points: Vec<String>,
+    pool_config: PoolConfig
+}
+
+impl Cluster {
+    /// Contact points are `host:port`, all nodes are expected on the same port.
+    pub fn new(contact_points: Vec<String>) -> Cluster {
+        Cluster {
+            contact_points: contact_points,
+            pool_config: PoolConfig::new()
+        }
+    }
+
+    /// Config of the pool opened to each node.
+    pub fn pool_config(mut self, pool_config: PoolConfig) -> Cluster {
+        self.pool_config = pool_config;
+        self
+    }
+
+    /// Discovers the nodes from the first contact point that answers and
+    /// opens a pool to each of them. A node that can't be reached keeps a
+    /// pool reconnecting in the background and is listed by
+    /// `Session::unreachable`, as long as one node can be reached.
+    pub fn connect(&self) -> Result<Session> {
+        let mut last_error = Error::new(ErrorKind::InvalidInput, "No contact points given");
+        for contact_point in self.contact_points.iter() {
+            match discover(contact_point, self.pool_config) {
+                Ok(nodes) => return open_session(nodes, self.pool_config),
+                Err(e) => last_error = e
+            }
+        }
+        Err(last_error)
+    }
+}
+
+// Opening the connection is bounded like a pool's, so a silent contact point
+// doesn't hold up the next one.
+fn discover(contact_point: &str, pool_config: PoolConfig) -> Result<Vec<Node>> {
+    let address = match try!(contact_point.to_socket_addrs()).next() {
+        Some(address) => address,
+        None => return Err(Error::new(ErrorKind::InvalidInput, format!("{} does not resolve", contact_point)))
+    };
+    let mut conn = try!(connect_with_timeout(contact_point.to_string(), pool_config.compression, pool_config.checkout_timeout));
+
+    let mut nodes = vec!();
+    for row in try!(query(&mut conn, LOCAL_QUERY)).iter() {
+        nodes.push(try!(read_node(row, address)));
+    }
+    for row in try!(query(&mut conn, PEERS_QUERY)).iter() {
+        let peer = try!(row.get_by_name_as("peer"));
+        let rpc_address = try!(row.get_by_name_as("rpc_address"));
+        if let Some(address) = peer_address(peer, rpc_address, address.port()) {
+            nodes.push(try!(read_node(row, address)));
+        }
+    }
+    Ok(nodes)
+}
+
+fn query(conn: &mut Connection, query: &str) -> Result<Vec<Row>> {
+    let statement = Statement::query(query).consistency(Consistency::One);
+    let (rows, _) = try!(conn.execute(statement).and_then(into_page));
+    Ok(rows)
+}
+
+fn read_node(row: &Row, address: SocketAddr) -> Result<Node> {
+    let tokens: Option<Vec<String>> = try!(row.get_by_name_as("tokens"));
+    Ok(Node {
+        address: address,
+        datacenter: try!(row.get_by_name_as("data_center")),
+        rack: try!(row.get_by_name_as("rack")),
+        tokens: tokens.unwrap_or_default(),
+        release_version: try!(row.get_by_name_as("release_version")),
+        host_id: try!(row.get_by_name_as("host_id"))
+    })
+}
+
+/// Where a peer takes client connections: its rpc_address, unless that is
+/// the wildcard address, in which case the address it gossips with.
+fn peer_address(peer: Option<IpAddr>, rpc_address: Option<IpAddr>, port: u16) -> Option<SocketAddr> {
+    match rpc_address {
+        Some(ip) if !ip.is_unspecified() => Some(SocketAddr::new(ip, port)),
+        _ => peer.map(|ip| SocketAddr::new(ip, port))
+    }
+}
+
+fn open_session(nodes: Vec<Node>, pool_config: PoolConfig) -> Result<Session> {
+    if nodes.is_empty() {
+        return Err(Error::new(ErrorKind::NotFound, "No nodes were discovered"));
+    }
+
+    // opened at once, so nodes that are down cost one checkout_timeout altogether
+    let opening: Vec<_> = nodes.iter()
+        .map(|node| {
+            let addr = node.address.to_string();
+            thread::spawn(move || Pool::new(addr, pool_config))
+        })
+        .collect();
+
+    let mut pools = vec!();
+    let mut unreachable = vec!();
+    for (node, opening) in nodes.iter().zip(opening) {
+        let opened = opening.join()
+            .unwrap_or_else(|_| Err(Error::new(ErrorKind::Other, "Opening the pool panicked")));
+        match opened {
+            Ok(pool) => pools.push(pool),
+            Err(e) => {
+                // kept to be used again once the node is back
+                pools.push(try!(Pool::reconnecting(node.address.to_string(), pool_config)));
+                unreachable.push((node.address, e));
+            }
+        }
+    }
+    if unreachable.len() == nodes.len() {
+        if let Some((_, e)) = unreachable.pop() {
+            return Err(e);
+        }
+    }
+    Ok(Session {
+        nodes: nodes,
+        pools: pools,
+        unreachable: unreachable,
+        next: AtomicUsize::new(0)
+    })
+}
+
+/// Pools to the nodes of a cluster, checked out from in turn.
+pub struct Session {
+    nodes: Vec<Node>,
+    pools: Vec<Pool>,
+    unreachable: Vec<(SocketAddr, Error)>,
+    next: AtomicUsize
+}
+
+impl Session {
+    /// Every node discovered, reachable or not.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Nodes that could not be reached when the session was opened, with
+    /// why. Their pools keep reconnecting, and are used once they do.
+    pub fn unreachable(&self) -> &[(SocketAddr, Error)] {
+        &self.unreachable
+    }
+
+    /// Checks out a connection from the next node, moving on to the others
+    /// while a node's pool can't hand one out. Nodes with no usable
+    /// connection are skipped rather than waited for.
+    pub fn get(&self) -> Result<PooledConnection> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut last_error = None;
+        for i in 0..self.pools.len() {
+            let pool = &self.pools[(start + i) % self.pools.len()];
+            if !pool.has_usable() {
+                continue;
+            }
+            match pool.get() {
+                Ok(conn) => return Ok(conn),
+                Err(e) => last_error = Some(e)
+            }
+        }
+        Err(last_error.unwrap_or_else(|| Error::new(ErrorKind::NotConnected, "No node has a usable connection")))
+    }
+
+    /// Runs a statement on the next node.
+    pub fn execute(&self, statement: Statement) -> Result<Response> {
+        let mut conn = try!(self.get());
+        conn.execute(statement)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{
+        IpAddr,
+        Ipv4Addr,
+        SocketAddr,
+        TcpListener
+    };
+    use std::time::{
+        Duration,
+        Instant
+    };
+
+    use shared::{
+        Consistency,
+        Response
+    };
+
+    use statement::Statement;
+
+    use pool::tests::ready_server;
+
+    use super::*;
+
+    #[test]
+    fn test_peer_address() {
+        let peer = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let rpc = IpAddr::V4(Ipv4Addr::new(192, 168, 0, 2));
+        let any = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+
+        assert_eq!(peer_address(Some(peer), Some(rpc), 9042), Some(SocketAddr::new(rpc, 9042)));
+        assert_eq!(peer_address(Some(peer), Some(any), 9042), Some(SocketAddr::new(peer, 9042)));
+        assert_eq!(peer_address(Some(peer), None, 9043), Some(SocketAddr::new(peer, 9043)));
+        assert_eq!(peer_address(None, None, 9042), None);
+    }
+
+    #[test]
+    fn test_no_contact_points() {
+        assert!(Cluster::new(vec!()).connect().is_err());
+    }
+
+    #[test]
+    fn test_silent_contact_point() {
+        // accepts connections and never answers STARTUP
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let cluster = Cluster::new(vec!(silent.local_addr().unwrap().to_string(), closed))
+            .pool_config(PoolConfig::new().checkout_timeout(Duration::from_millis(50)));
+
+        // the silent one is given up on and the second one tried
+        let started = Instant::now();
+        let err = cluster.connect().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    fn node(address: SocketAddr) -> Node {
+        Node {
+            address: address,
+            datacenter: None,
+            rack: None,
+            tokens: vec!(),
+            release_version: None,
+            host_id: None
+        }
+    }
+
+    #[test]
+    fn test_unreachable_node() {
+        let config = PoolConfig::new().reconnect_interval(Duration::from_secs(60));
+        let up: SocketAddr = ready_server().parse().unwrap();
+        let down: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let session = open_session(vec!(node(down), node(up)), config).unwrap();
+        assert_eq!(session.nodes().len(), 2);
+        assert_eq!(session.unreachable().len(), 1);
+        assert_eq!(session.unreachable()[0].0, down);
+
+        // checkouts skip the node that is down
+        for _ in 0..4 {
+            session.get().unwrap();
+        }
+
+        assert!(open_session(vec!(node(down)), config).is_err());
+    }
+
+    #[ignore]
+    #[test]
+    fn test_session() {
+        // the first contact point is not listening
+        let cluster = Cluster::new(vec!("127.0.0.1:1".to_string(), "127.0.0.1:9042".to_string()));
+        let session = cluster.connect().unwrap();
+        for node in session.nodes() {
+            println!("{} in {:?}/{:?}, {} tokens, release {:?}",
+                node.address, node.datacenter, node.rack, node.tokens.len(), node.release_version);
+        }
+
+        let statement = Statement::query("SELECT * FROM testing.users where super_key = 1").consistency(Consistency::One);
+        match session.execute(statement).unwrap() {
+            Response::Result(_) => {}
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+}
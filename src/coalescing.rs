@@ -0,0 +1,290 @@
+//! Holding frames back to write several with one flush.
+//!
+//! The write half of a connection is shared with a flusher thread, started
+//! once coalescing is turned on, which flushes whatever is pending when its
+//! `max_delay` is up, whether or not anything else is sent.
+
+use std::io::{
+    Result,
+    Error,
+    ErrorKind,
+    BufWriter,
+    Write
+};
+
+use std::net::TcpStream;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+    Condvar
+};
+
+use std::thread;
+use std::time::{
+    Duration,
+    Instant
+};
+
+use shared::Compression;
+
+use writing::write_segments;
+
+/// When to flush frames sent without waiting for their responses. Pending
+/// frames are flushed once `max_bytes` of them are waiting or `max_delay`
+/// after the first of them was sent, and always before a response is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coalescing {
+    pub max_delay: Duration,
+    pub max_bytes: usize
+}
+
+impl Coalescing {
+    pub fn new(max_delay: Duration, max_bytes: usize) -> Coalescing {
+        Coalescing {
+            max_delay: max_delay,
+            max_bytes: max_bytes
+        }
+    }
+
+    /// Whether `bytes` pending since `since` should be flushed at `now`.
+    pub fn due(&self, bytes: usize, since: Instant, now: Instant) -> bool {
+        bytes >= self.max_bytes || now.duration_since(since) >= self.max_delay
+    }
+}
+
+impl Default for Coalescing {
+    fn default() -> Coalescing {
+        Coalescing::new(Duration::from_millis(1), 64 * 1024)
+    }
+}
+
+struct State {
+    out: BufWriter<TcpStream>,
+    coalescing: Option<Coalescing>,
+    // bytes written since the last flush, and when the first of them was
+    unflushed: usize,
+    since: Option<Instant>,
+    // why writing failed, errors are not Clone
+    failed: Option<(ErrorKind, String)>,
+    flusher: bool,
+    closed: bool
+}
+
+impl State {
+    fn check(&self) -> Result<()> {
+        match self.failed {
+            Some((kind, ref message)) => Err(Error::new(kind, message.clone())),
+            None => Ok(())
+        }
+    }
+
+    fn fail(&mut self, e: Error) -> Error {
+        self.failed = Some((e.kind(), e.to_string()));
+        e
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        try!(self.check());
+        if self.since.is_some() {
+            if let Err(e) = self.out.flush() {
+                return Err(self.fail(e));
+            }
+            self.unflushed = 0;
+            self.since = None;
+        }
+        Ok(())
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // notified when frames start pending, coalescing changes or the writer is dropped
+    changed: Condvar
+}
+
+impl Shared {
+    fn lock<'a>(&'a self) -> MutexGuard<'a, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// The write half of a connection.
+pub(crate) struct Writer {
+    shared: Arc<Shared>
+}
+
+impl Writer {
+    pub(crate) fn new(stream: TcpStream) -> Writer {
+        Writer {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    out: BufWriter::new(stream),
+                    coalescing: None,
+                    unflushed: 0,
+                    since: None,
+                    failed: None,
+                    flusher: false,
+                    closed: false
+                }),
+                changed: Condvar::new()
+            })
+        }
+    }
+
+    /// Without coalescing every frame is flushed as it is written.
+    pub(crate) fn set_coalescing(&self, coalescing: Option<Coalescing>) -> Result<()> {
+        let mut state = self.shared.lock();
+        if let Some(coalescing) = coalescing {
+            // the buffer writes on its own once full, so it has to hold max_bytes
+            if state.out.capacity() < coalescing.max_bytes {
+                try!(state.flush());
+                let stream = try!(state.out.get_ref().try_clone());
+                state.out = BufWriter::with_capacity(coalescing.max_bytes, stream);
+            }
+        }
+        state.coalescing = coalescing;
+        if coalescing.is_none() {
+            try!(state.flush());
+        } else if !state.flusher {
+            state.flusher = true;
+            let shared = self.shared.clone();
+            thread::spawn(move || flush_when_due(shared));
+        }
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    /// Writes a frame, split into segments when `segments` is given.
+    pub(crate) fn write(&self, frame: &[u8], segments: Option<Compression>) -> Result<()> {
+        let mut state = self.shared.lock();
+        try!(state.check());
+        let written = match segments {
+            Some(compression) => write_segments(&mut state.out, frame, compression),
+            None => state.out.write_all(frame)
+        };
+        if let Err(e) = written {
+            return Err(state.fail(e));
+        }
+
+        let now = Instant::now();
+        state.unflushed += frame.len();
+        let first = state.since.is_none();
+        let since = *state.since.get_or_insert(now);
+        let due = match state.coalescing {
+            Some(ref coalescing) => coalescing.due(state.unflushed, since, now),
+            None => true
+        };
+        if due {
+            try!(state.flush());
+        } else if first {
+            self.shared.changed.notify_all();
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&self) -> Result<()> {
+        self.shared.lock().flush()
+    }
+
+    /// Whether writing failed, here or in the flusher thread.
+    pub(crate) fn failed(&self) -> bool {
+        self.shared.lock().failed.is_some()
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+// Flushes pending frames once their max_delay is up, until the writer is dropped.
+fn flush_when_due(shared: Arc<Shared>) {
+    let mut state = shared.lock();
+    loop {
+        if state.closed {
+            return;
+        }
+        let deadline = match (state.coalescing, state.since) {
+            (Some(coalescing), Some(since)) => Some(since + coalescing.max_delay),
+            _ => None
+        };
+        state = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    // a failure is kept for the next write to report
+                    let _ = state.flush();
+                    continue;
+                }
+                shared.changed.wait_timeout(state, deadline - now)
+                    .map(|(state, _)| state)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner().0)
+            }
+            None => shared.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner())
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::{
+        Duration,
+        Instant
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let coalescing = Coalescing::new(Duration::from_millis(5), 100);
+        let since = Instant::now();
+
+        assert!(!coalescing.due(99, since, since));
+        assert!(coalescing.due(100, since, since));
+        assert!(coalescing.due(1, since, since + Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_flush_on_timer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+
+        let writer = Writer::new(stream);
+        writer.set_coalescing(Some(Coalescing::new(Duration::from_millis(20), 1024))).unwrap();
+        let sent = Instant::now();
+        writer.write(&[1, 2, 3], None).unwrap();
+
+        // nothing else is written or read, the flusher sends it on its own
+        let mut received = [0u8; 3];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(received, [1, 2, 3]);
+        assert!(sent.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_flush_at_max_bytes() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        peer.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        let writer = Writer::new(stream);
+        writer.set_coalescing(Some(Coalescing::new(Duration::from_secs(60), 64 * 1024))).unwrap();
+
+        // frames larger than the default buffer are still held back
+        writer.write(&[1; 20 * 1024], None).unwrap();
+        writer.write(&[2; 20 * 1024], None).unwrap();
+        assert!(peer.read(&mut [0u8; 1]).is_err());
+
+        writer.write(&[3; 30 * 1024], None).unwrap();
+        let mut received = vec![0u8; 70 * 1024];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(received[70 * 1024 - 1], 3);
+    }
+}
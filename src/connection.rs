@@ -2,19 +2,29 @@ use std::io::{
     Result,
     Error,
     ErrorKind,
-    Write
+    BufReader
 };
 
-use std::net::TcpStream;
-use std::collections::HashMap;
+use std::net::{
+    TcpStream,
+    ToSocketAddrs
+};
+use std::sync::Arc;
+use std::time::{
+    Duration,
+    Instant
+};
+use std::collections::{
+    HashMap,
+    VecDeque
+};
 use std::net::SocketAddr;
 use std::str::FromStr;
 
 
 use futures::Future;
+use uuid::Uuid;
 use tokio_core;
-use bufstream;
-use bufstream::BufStream;
 
 use shared;
 
@@ -22,13 +32,61 @@ use shared::{
     Request,
     Consistency,
     Response,
+    Envelope,
     BatchQuery,
     Column,
-    ResultBody
+    ColumnSpec,
+    ResultBody,
+    ProtocolVersion,
+    Compression
+};
+
+use mapping::{
+    IntoValues,
+    IntoNamedValues
+};
+
+use statement::{
+    Statement,
+    Batch,
+    Target
+};
+
+use paging::{
+    RowIterator,
+    PageIterator,
+    into_page
+};
+
+use cursor::{
+    Cursor,
+    Page
+};
+
+use timestamp::TimestampGenerator;
+use tracing::{
+    Trace,
+    load_trace
+};
+use lwt::LwtResult;
+
+use reading::reader::{
+    ReadMessage,
+    Frame,
+    read_frame,
+    decode_envelope,
+    decode_rows_page
+};
+
+use borrowed::RowsPage;
+use reading::segment::SegmentBuffer;
+use writing::{
+    WriteMessage,
+    FrameEncoder
 };
 
-use reading::reader::ReadMessage;
-use writing::WriteMessage;
+use coalescing::Writer;
+pub use coalescing::Coalescing;
 
 use tokio_core::reactor::Core;
 
@@ -42,7 +100,7 @@ macro_rules! t {
 
 pub fn tokio_connect() {
 
-    let msg = startup_request();
+    let msg = startup_request(ProtocolVersion::V4, Compression::None);
 
     let mut req = Vec::new();
 
@@ -104,36 +162,179 @@ pub fn tokio_connect() {
     }*/
 }
 
+// What reading the response to a request needs.
+struct InFlight {
+    // id of the prepared statement executed, to keep a changed result metadata id
+    prepared_id: Option<Vec<u8>>,
+    // result metadata, for rows sent without it
+    metadata: Option<Arc<Vec<ColumnSpec>>>,
+    // nobody will ask for the response, it is dropped when it arrives
+    abandoned: bool
+}
+
 pub struct Connection {
-    buf: BufStream<TcpStream>
+    reader: BufReader<TcpStream>,
+    // shared with the thread flushing coalesced frames
+    writer: Writer,
+    version: ProtocolVersion,
+    compression: Compression,
+    // unread part of the last v5 segment, None before v5
+    segments: Option<SegmentBuffer>,
+    // reused for every frame sent
+    encoder: FrameEncoder,
+    // result metadata of prepared statements, for executing them with skip_metadata
+    prepared: HashMap<Vec<u8>, Arc<Vec<ColumnSpec>>>,
+    // result metadata ids of prepared statements, sent with EXECUTE from v5 on
+    result_metadata_ids: HashMap<Vec<u8>, Vec<u8>>,
+    // requests whose responses are still to be read, by stream id
+    in_flight: HashMap<i16, InFlight>,
+    // responses read while waiting for another stream, in arrival order
+    arrived: VecDeque<Frame>,
+    // where to start looking for a free stream id
+    next_stream: i16,
+    timestamp_generator: Option<Arc<dyn TimestampGenerator>>,
+    warning_hook: Option<Arc<WarningHook>>,
+    // of the last response read
+    last_warnings: Vec<String>,
+    // set once reading or writing failed, leaving the stream out of step
+    broken: bool
 }
 
-fn startup_request() -> Request {
+/// Called with the warnings of every response that has any.
+pub type WarningHook = dyn Fn(&[String]) + Send + Sync;
+
+/// A warning hook printing to stderr: `conn.set_warning_hook(Some(Arc::new(log_warnings)))`.
+pub fn log_warnings(warnings: &[String]) {
+    for warning in warnings.iter() {
+        eprintln!("Server warning: {}", warning);
+    }
+}
+
+pub(crate) fn startup_request(version: ProtocolVersion, compression: Compression) -> Request {
     let mut body = HashMap::new();
     body.insert("CQL_VERSION".to_string(), "3.4.3".to_string());
+    // only v5 segments are compressed, older versions would compress whole frames
+    if version.has_segments() {
+        if let Some(name) = compression_name(compression) {
+            body.insert("COMPRESSION".to_string(), name.to_string());
+        }
+    }
 
     Request::Startup(body)
 }
 
+fn compression_name(compression: Compression) -> Option<&'static str> {
+    match compression {
+        Compression::None => None,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => Some("lz4")
+    }
+}
+
+// error code of protocol errors, which is also how an unsupported version is refused
+static PROTOCOL_ERROR: u32 = 0x000A;
+
+/// Connects with the newest protocol version the server accepts, stepping
+/// down a version whenever the server refuses one.
 pub fn connect(addr: String) -> Result<Connection> {
+    negotiate(&addr, ProtocolVersion::highest(), Compression::None, None)
+}
+
+/// Like `connect`, compressing v5 segments. Older versions are not compressed.
+pub fn connect_with_compression(addr: String, compression: Compression) -> Result<Connection> {
+    negotiate(&addr, ProtocolVersion::highest(), compression, None)
+}
+
+/// Like `connect_with_compression`, failing with `TimedOut` when connecting
+/// and starting up take longer than `timeout` altogether.
+pub fn connect_with_timeout(addr: String, compression: Compression, timeout: Duration) -> Result<Connection> {
+    negotiate(&addr, ProtocolVersion::highest(), compression, Some(Instant::now() + timeout))
+}
+
+/// Connects with exactly the given protocol version.
+pub fn connect_with_version(addr: String, version: ProtocolVersion) -> Result<Connection> {
+    let (stream, reader, msg) = try!(startup(&addr, version, Compression::None, None));
+    open(stream, reader, msg, version, Compression::None)
+}
+
+fn negotiate(addr: &str, highest: ProtocolVersion, compression: Compression, deadline: Option<Instant>) -> Result<Connection> {
+    let mut version = highest;
+    loop {
+        let (stream, reader, msg) = try!(startup(addr, version, compression, deadline));
+        if let Response::Error(code, ref message) = msg {
+            let message = message.to_lowercase();
+            if code == PROTOCOL_ERROR && message.contains("protocol") && message.contains("version") {
+                // the version settled on is reported by protocol_version()
+                if let Some(lower) = version.lower() {
+                    version = lower;
+                    continue;
+                }
+            }
+        }
+        return open(stream, reader, msg, version, compression);
+    }
+}
 
-    let stream = try!(TcpStream::connect(&*addr));
+fn startup(addr: &str, version: ProtocolVersion, compression: Compression, deadline: Option<Instant>) -> Result<(TcpStream, BufReader<TcpStream>, Response)> {
+    let mut stream = match deadline {
+        Some(deadline) => try!(connect_before(addr, deadline)),
+        None => try!(TcpStream::connect(addr))
+    };
+    let mut reader = BufReader::new(try!(stream.try_clone()));
+
+    // a server that accepts but never answers would otherwise block forever
+    if let Some(deadline) = deadline {
+        let timeout = try!(time_left(deadline));
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+    }
+
+    let startup_msg = startup_request(version, compression);
+    try!(stream.write_message_with_version(startup_msg, version));
+
+    let msg = try!(reader.read_message().map_err(|e| match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut =>
+            Error::new(ErrorKind::TimedOut, "Timed out waiting for the server to start up"),
+        _ => e
+    }));
+    if deadline.is_some() {
+        try!(stream.set_read_timeout(None));
+        try!(stream.set_write_timeout(None));
+    }
+    Ok((stream, reader, msg))
+}
 
-    let startup_msg = startup_request();
-    let mut buf = BufStream::new(stream);
-    try!(buf.write_message(startup_msg));
-    try!(buf.flush());
+// Tries each address `addr` resolves to until one accepts before `deadline`.
+fn connect_before(addr: &str, deadline: Instant) -> Result<TcpStream> {
+    let mut last_error = Error::new(ErrorKind::InvalidInput, format!("{} does not resolve", addr));
+    for address in try!(addr.to_socket_addrs()) {
+        let timeout = try!(time_left(deadline));
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e
+        }
+    }
+    Err(last_error)
+}
 
-    let msg = try!(buf.read_message());
+fn time_left(deadline: Instant) -> Result<Duration> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(Error::new(ErrorKind::TimedOut, "Timed out opening a connection"));
+    }
+    Ok(deadline - now)
+}
+
+fn open(stream: TcpStream, reader: BufReader<TcpStream>, msg: Response, version: ProtocolVersion, compression: Compression) -> Result<Connection> {
     match msg {
         Response::Ready => {
             println!("No auth required by server - moving on");
-            let cli = Connection { buf: buf };
+            let cli = Connection::new(stream, reader, version, compression);
             Ok(cli)
         }
         Response::Authenticate(_) => {
             println!("Auth required - sending credentials - maybe");
-            let cli = Connection { buf: buf };
+            let cli = Connection::new(stream, reader, version, compression);
             Ok(cli)
         }
         _ => {
@@ -145,78 +346,366 @@ pub fn connect(addr: String) -> Result<Connection> {
 
 
 impl Connection {
-    pub fn query(&mut self, query: String, consistency: Consistency) -> Result<Response> {
-        let message = Request::Query(query, consistency);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
+    fn new(stream: TcpStream, reader: BufReader<TcpStream>, version: ProtocolVersion, compression: Compression) -> Connection {
+        Connection {
+            reader: reader,
+            writer: Writer::new(stream),
+            version: version,
+            compression: compression,
+            segments: if version.has_segments() { Some(SegmentBuffer::new(compression)) } else { None },
+            encoder: FrameEncoder::new(),
+            prepared: HashMap::new(),
+            result_metadata_ids: HashMap::new(),
+            in_flight: HashMap::new(),
+            arrived: VecDeque::new(),
+            next_stream: 0,
+            timestamp_generator: None,
+            warning_hook: None,
+            last_warnings: vec!(),
+            broken: false
+        }
+    }
 
-        Ok(try!(self.buf.read_message()))
+    /// The protocol version agreed on with the server.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        self.version
     }
-    pub fn prm_query(&mut self, query: String, values: Vec<Column>, consistency: Consistency) -> Result<Response> {
-        let message = Request::PrmQuery(query, values, consistency);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Hands server warnings to `hook` as they arrive, e.g. to log them.
+    /// They are also on the envelopes of `execute_envelope` and `batch_envelope`,
+    /// on pages and iterators, and kept until the next response in `last_warnings`.
+    pub fn set_warning_hook(&mut self, hook: Option<Arc<WarningHook>>) {
+        self.warning_hook = hook;
     }
-    pub fn prm_query_with_names(&mut self, query: String, named_values: Vec<(String, Column)>, consistency: Consistency) -> Result<Response> {
-        let message = Request::PrmQueryWithNames(query, named_values, consistency);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Warnings of the last response read, for calls that return a bare
+    /// `Response` such as `query` or `paged_query`. Empty if it had none.
+    pub fn last_warnings(&self) -> &[String] {
+        &self.last_warnings
     }
-    pub fn prepare(&mut self, query: String) -> Result<Response> {
-        let message = Request::Prepare(query);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Stamps every statement and batch that has no default timestamp of its own
+    /// with one from `generator`. Without a generator the server's clock is used.
+    pub fn set_timestamp_generator(&mut self, generator: Option<Arc<dyn TimestampGenerator>>) {
+        self.timestamp_generator = generator;
     }
-    pub fn execute(&mut self, id: Vec<u8>, values: Vec<Column>, consistency: Consistency) -> Result<Response> {
-        let message = Request::Execute(id, values, consistency);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Holds frames back to write several with one flush, see `Coalescing`.
+    /// Without it every frame is flushed as it is sent.
+    pub fn set_coalescing(&mut self, coalescing: Option<Coalescing>) -> Result<()> {
+        let set = self.writer.set_coalescing(coalescing);
+        if set.is_err() {
+            self.broken = true;
+        }
+        set
     }
-    pub fn execute_batch(&mut self, queries: Vec<BatchQuery>, consistency: Consistency) -> Result<Response> {
-        let message = Request::Batch(queries, consistency);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Writes out every frame held back by coalescing.
+    pub fn flush(&mut self) -> Result<()> {
+        let flushed = self.writer.flush();
+        if flushed.is_err() {
+            self.broken = true;
+        }
+        flushed
     }
 
+    /// Sends a statement without waiting for its response, to pipeline several.
+    /// Returns the stream id its response will come back on.
+    pub fn submit(&mut self, statement: Statement) -> Result<i16> {
+        self.send(Request::Statement(statement))
+    }
 
+    /// Like `submit`, for a batch.
+    pub fn submit_batch(&mut self, batch: Batch) -> Result<i16> {
+        self.send(Request::Batch(batch))
+    }
 
-    pub fn paged_query(&mut self, query: String, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
-        let message = Request::PagedQuery(query, consistency, result_page_size, paging_state);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
+    /// The next response to arrive for any submitted request, with its stream
+    /// id. The server may answer requests in any order.
+    pub fn next_response(&mut self) -> Result<(i16, Response)> {
+        if self.in_flight() == 0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "No request is waiting for a response"));
+        }
+        let frame = try!(self.frame_for(None));
+        let stream = frame.stream;
+        self.decode(frame).map(|envelope| (stream, envelope.response))
+    }
+
+    /// The response to the request submitted on `stream`.
+    pub fn response(&mut self, stream: i16) -> Result<Response> {
+        self.receive(stream)
+    }
+
+    /// Number of requests sent whose responses haven't been read.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.values().filter(|request| !request.abandoned).count()
+    }
+
+    /// Whether reading or writing failed, so that the connection can't be used anymore.
+    pub fn is_broken(&self) -> bool {
+        self.broken || self.writer.failed()
+    }
+
+    /// Writes a request without waiting for its response and returns the
+    /// stream id it was sent on.
+    pub(crate) fn send(&mut self, mut message: Request) -> Result<i16> {
+        if let Some(ref generator) = self.timestamp_generator {
+            match message {
+                Request::Statement(ref mut statement) if statement.default_timestamp.is_none() =>
+                    statement.default_timestamp = Some(generator.next_timestamp()),
+                Request::Batch(ref mut batch) if batch.default_timestamp.is_none() =>
+                    batch.default_timestamp = Some(generator.next_timestamp()),
+                _ => {}
+            }
+        }
+        let mut metadata = None;
+        let mut prepared_id = None;
+        if let Request::Statement(ref mut statement) = message {
+            if let Target::Prepared(ref id) = statement.target {
+                prepared_id = Some(id.clone());
+                if statement.result_metadata_id.is_none() {
+                    statement.result_metadata_id = self.result_metadata_ids.get(id).cloned();
+                }
+            }
+            if statement.skip_metadata {
+                if let Target::Prepared(ref id) = statement.target {
+                    metadata = self.prepared.get(id).cloned();
+                }
+                // without known metadata the rows could not be decoded
+                statement.skip_metadata = metadata.is_some();
+            }
+        }
+        let stream = try!(self.allocate_stream());
+        let frame = try!(self.encoder.encode(&message, self.version, stream));
+        let segments = if self.segments.is_some() { Some(self.compression) } else { None };
+        if let Err(e) = self.writer.write(frame, segments) {
+            self.broken = true;
+            return Err(e);
+        }
+        self.in_flight.insert(stream, InFlight { prepared_id: prepared_id, metadata: metadata, abandoned: false });
+        Ok(stream)
+    }
+    fn allocate_stream(&mut self) -> Result<i16> {
+        for _ in 0..(i16::MAX as usize + 1) {
+            let stream = self.next_stream;
+            self.next_stream = if stream == i16::MAX { 0 } else { stream + 1 };
+            if !self.in_flight.contains_key(&stream) {
+                return Ok(stream);
+            }
+        }
+        Err(Error::new(ErrorKind::Other, "Every stream id is in use"))
+    }
+    /// Reads the response to the request sent on `stream`.
+    pub(crate) fn receive(&mut self, stream: i16) -> Result<Response> {
+        self.receive_envelope(stream).map(|envelope| envelope.response)
+    }
+    pub(crate) fn receive_envelope(&mut self, stream: i16) -> Result<Envelope> {
+        let frame = try!(self.frame_for(Some(stream)));
+        self.decode(frame)
+    }
+    // frees the frame's stream id
+    fn decode(&mut self, frame: Frame) -> Result<Envelope> {
+        let request = self.in_flight.remove(&frame.stream);
+        let (prepared_id, metadata) = request.map_or((None, None), |request| (request.prepared_id, request.metadata));
+        let envelope = try!(decode_envelope(frame, metadata));
+        self.notify_warnings(&envelope.warnings);
+        if let (Some(id), Some((result_metadata_id, specs))) = (prepared_id, envelope.metadata_changed.clone()) {
+            self.prepared.insert(id.clone(), specs);
+            self.result_metadata_ids.insert(id, result_metadata_id);
+        }
+        if let Response::Result(ResultBody::Prepared(ref id, ref specs, ref result_metadata_id)) = envelope.response {
+            self.prepared.insert(id.clone(), specs.clone());
+            if let Some(ref result_metadata_id) = *result_metadata_id {
+                self.result_metadata_ids.insert(id.clone(), result_metadata_id.clone());
+            }
+        }
+        Ok(envelope)
+    }
+    /// Like `receive`, for a rows result decoded without copying.
+    pub(crate) fn receive_page(&mut self, stream: i16) -> Result<RowsPage> {
+        let frame = try!(self.frame_for(Some(stream)));
+        let request = self.in_flight.remove(&stream);
+        let (prepared_id, metadata) = request.map_or((None, None), |request| (request.prepared_id, request.metadata));
+        let mut page = try!(decode_rows_page(frame, metadata));
+        self.notify_warnings(&page.warnings);
+        if let (Some(id), Some(result_metadata_id)) = (prepared_id, page.new_metadata_id.take()) {
+            self.prepared.insert(id.clone(), page.specs.clone());
+            self.result_metadata_ids.insert(id, result_metadata_id);
+        }
+        Ok(page)
+    }
+    /// Gives up on the response to the request sent on `stream`, which is
+    /// then dropped when it arrives instead of being waited for.
+    pub(crate) fn abandon(&mut self, stream: i16) {
+        if let Some(index) = self.arrived.iter().position(|frame| frame.stream == stream) {
+            self.arrived.remove(index);
+            self.in_flight.remove(&stream);
+        } else if let Some(request) = self.in_flight.get_mut(&stream) {
+            request.abandoned = true;
+        }
+    }
+    fn waiting(&self, stream: i16) -> bool {
+        self.in_flight.get(&stream).is_some_and(|request| !request.abandoned)
+    }
+    /// The response on `stream`, or on any stream in flight when `None`.
+    /// Responses to other requests read on the way are kept for later.
+    fn frame_for(&mut self, stream: Option<i16>) -> Result<Frame> {
+        if let Some(stream) = stream {
+            if !self.waiting(stream) {
+                return Err(Error::new(ErrorKind::InvalidInput, format!("No request is waiting on stream {}", stream)));
+            }
+        }
+        let wanted = |frame: &Frame| stream.is_none() || stream == Some(frame.stream);
+        if let Some(index) = self.arrived.iter().position(wanted) {
+            return Ok(self.arrived.remove(index).unwrap());
+        }
+        loop {
+            let frame = try!(self.next_frame());
+            if !self.waiting(frame.stream) {
+                // not an answer to anything sent, such as an event on stream -1,
+                // or an abandoned one whose stream id is free again
+                self.in_flight.remove(&frame.stream);
+                continue;
+            }
+            if wanted(&frame) {
+                return Ok(frame);
+            }
+            self.arrived.push_back(frame);
+        }
+    }
+    fn next_frame(&mut self) -> Result<Frame> {
+        // the response may be to a frame still held back
+        try!(self.flush());
+        let frame = match self.segments {
+            Some(ref mut segments) => read_frame(&mut segments.reader(&mut self.reader)),
+            None => read_frame(&mut self.reader)
+        };
+        if frame.is_err() {
+            self.broken = true;
+        }
+        frame
+    }
+    fn notify_warnings(&mut self, warnings: &[String]) {
+        self.last_warnings = warnings.to_vec();
+        if let Some(ref hook) = self.warning_hook {
+            if !warnings.is_empty() {
+                hook(warnings);
+            }
+        }
+    }
+
+    /// Runs a QUERY or EXECUTE request with all the options set on the statement.
+    pub fn execute(&mut self, statement: Statement) -> Result<Response> {
+        self.execute_envelope(statement).map(|envelope| envelope.response)
+    }
+    /// Like `execute` for statements returning rows, keeping the page in the
+    /// frame it arrived in instead of decoding every value.
+    pub fn execute_borrowed(&mut self, statement: Statement) -> Result<RowsPage> {
+        let stream = try!(self.send(Request::Statement(statement)));
+        self.receive_page(stream)
+    }
+    /// Like `execute`, also returning the tracing id, warnings and custom payload of the response.
+    pub fn execute_envelope(&mut self, statement: Statement) -> Result<Envelope> {
+        let stream = try!(self.send(Request::Statement(statement)));
+        self.receive_envelope(stream)
+    }
+
+    pub fn query(&mut self, query: String, consistency: Consistency) -> Result<Response> {
+        self.execute(Statement::query(query).consistency(consistency))
+    }
+    pub fn prm_query<V: IntoValues>(&mut self, query: String, values: V, consistency: Consistency) -> Result<Response> {
+        self.execute(Statement::query(query).values(values).consistency(consistency))
+    }
+    pub fn prm_query_with_names<N: IntoNamedValues>(&mut self, query: String, named_values: N, consistency: Consistency) -> Result<Response> {
+        self.execute(Statement::query(query).named_values(named_values).consistency(consistency))
+    }
+    pub fn prepare(&mut self, query: String) -> Result<Response> {
+        let stream = try!(self.send(Request::Prepare(query)));
+        self.receive(stream)
+    }
+    pub fn execute_prepared<V: IntoValues>(&mut self, id: Vec<u8>, values: V, consistency: Consistency) -> Result<Response> {
+        self.execute(Statement::prepared(id).values(values).consistency(consistency))
+    }
+    pub fn execute_batch(&mut self, queries: Vec<BatchQuery>, consistency: Consistency) -> Result<Response> {
+        let mut batch = Batch::logged().consistency(consistency);
+        batch.statements = queries.into_iter().map(From::from).collect();
+        self.batch(batch)
+    }
+    /// Runs a BATCH request with all the options set on the batch.
+    pub fn batch(&mut self, batch: Batch) -> Result<Response> {
+        self.batch_envelope(batch).map(|envelope| envelope.response)
+    }
+    pub fn batch_envelope(&mut self, batch: Batch) -> Result<Envelope> {
+        let stream = try!(self.send(Request::Batch(batch)));
+        self.receive_envelope(stream)
+    }
 
-        Ok(try!(self.buf.read_message()))
+    /// Loads the trace of a request sent with tracing, waiting a little
+    /// for the server to finish writing it.
+    pub fn trace(&mut self, session_id: Uuid) -> Result<Trace> {
+        load_trace(self, session_id)
+    }
+
+    /// Runs a conditional statement and reports whether it was applied.
+    pub fn execute_lwt(&mut self, statement: Statement) -> Result<LwtResult> {
+        self.execute(statement).and_then(LwtResult::from_response)
+    }
+    /// Runs a batch of conditional statements and reports whether it was applied.
+    pub fn batch_lwt(&mut self, batch: Batch) -> Result<LwtResult> {
+        self.batch(batch).and_then(LwtResult::from_response)
     }
-    pub fn paged_prm_query(&mut self, query: String, values: Vec<Column>, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
-        let message = Request::PagedPrmQuery(query, values, consistency, result_page_size, paging_state);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+
+
+    pub fn paged_query(&mut self, query: String, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
+        self.execute(Statement::query(query).consistency(consistency).page_size(result_page_size).paging_state(paging_state))
+    }
+    pub fn paged_prm_query<V: IntoValues>(&mut self, query: String, values: V, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
+        self.execute(Statement::query(query).values(values).consistency(consistency).page_size(result_page_size).paging_state(paging_state))
+    }
+    pub fn paged_prm_query_with_names<N: IntoNamedValues>(&mut self, query: String, named_values: N, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
+        self.execute(Statement::query(query).named_values(named_values).consistency(consistency).page_size(result_page_size).paging_state(paging_state))
+    }
+    pub fn paged_execute<V: IntoValues>(&mut self, id: Vec<u8>, values: V, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
+        self.execute(Statement::prepared(id).values(values).consistency(consistency).page_size(result_page_size).paging_state(paging_state))
     }
-    pub fn paged_prm_query_with_names(&mut self, query: String, named_values: Vec<(String, Column)>, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
-        let message = Request::PagedPrmQueryWithNames(query, named_values, consistency, result_page_size, paging_state);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Iterates over all rows of the statement, fetching pages of its page size.
+    pub fn iter(&mut self, statement: Statement) -> RowIterator {
+        RowIterator::new(self, statement)
+    }
+    /// Pages through `statement` one lazily decoded page at a time.
+    pub fn pages(&mut self, statement: Statement) -> PageIterator<'_> {
+        PageIterator::new(self, statement)
+    }
+    pub fn iter_query(&mut self, query: String, consistency: Consistency, result_page_size: i32) -> RowIterator {
+        self.iter(Statement::query(query).consistency(consistency).page_size(result_page_size))
+    }
+    pub fn iter_prm_query<V: IntoValues>(&mut self, query: String, values: V, consistency: Consistency, result_page_size: i32) -> RowIterator {
+        self.iter(Statement::query(query).values(values).consistency(consistency).page_size(result_page_size))
+    }
+    pub fn iter_prm_query_with_names<N: IntoNamedValues>(&mut self, query: String, named_values: N, consistency: Consistency, result_page_size: i32) -> RowIterator {
+        self.iter(Statement::query(query).named_values(named_values).consistency(consistency).page_size(result_page_size))
+    }
+    pub fn iter_execute<V: IntoValues>(&mut self, id: Vec<u8>, values: V, consistency: Consistency, result_page_size: i32) -> RowIterator {
+        self.iter(Statement::prepared(id).values(values).consistency(consistency).page_size(result_page_size))
     }
-    pub fn paged_execute(&mut self, id: Vec<u8>, values: Vec<Column>, consistency: Consistency, result_page_size: i32, paging_state: Option<Vec<u8>>) -> Result<Response> {
-        let message = Request::PagedExecute(id, values, consistency, result_page_size, paging_state);
-        try!(self.buf.write_message(message));
-        try!(self.buf.flush());
 
-        Ok(try!(self.buf.read_message()))
+    /// Fetches one page and returns a cursor token for the next one, signed with
+    /// `secret`. Pass the token back (with the same statement and secret) to
+    /// continue, e.g. from a later HTTP request.
+    pub fn fetch_page(&mut self, statement: Statement, cursor: Option<&str>, secret: &[u8]) -> Result<Page> {
+        let paging_state = match cursor {
+            Some(token) => Some(try!(try!(Cursor::from_token(token, secret)).resume(&statement))),
+            None => None
+        };
+        let envelope = try!(self.execute_envelope(statement.clone().paging_state(paging_state)));
+        let (rows, paging_state) = try!(into_page(envelope.response));
+        let cursor = match paging_state {
+            Some(paging_state) => Some(try!(Cursor::new(&statement, paging_state, secret)).to_token()),
+            None => None
+        };
+        Ok(Page { rows: rows, cursor: cursor, warnings: envelope.warnings })
     }
 }
 
@@ -268,6 +757,12 @@ fn test_query() {
 
     println!("Result of prm_query was {:?}", response);
 
+    let query = "SELECT * FROM testing.users where user_id = ? and age = ? ALLOW FILTERING".to_string();
+
+    let response = conn.prm_query(query, ("jsmith", 42), Consistency::Quorum).unwrap();
+
+    println!("Result of prm_query with native values was {:?}", response);
+
     let query = "SELECT * FROM testing.users where user_id = :user_id".to_string();
     let named_values = vec![("user_id".to_string(), shared::Column::String("jsmith".to_string()))];
 
@@ -331,4 +826,332 @@ fn test_paging() {
             println!("Result of first paged_prm_query was {:?}", response);
         }
     }
-}
\ No newline at end of file
+}
+
+#[ignore]
+#[test]
+fn test_row_iterator() {
+    let mut conn = connect("127.0.0.1:9042".to_string()).unwrap();
+
+    let query = "SELECT * FROM testing.users where super_key = 1 and user_id > ?".to_string();
+
+    let mut count = 0;
+    for row in conn.iter_prm_query(query.clone(), (1i64,), Consistency::Quorum, 10) {
+        row.unwrap();
+        count += 1;
+    }
+
+    let prefetched = conn.iter_prm_query(query, (1i64,), Consistency::Quorum, 10).prefetch(true).count();
+
+    println!("Row iterator read {} rows, {} with prefetch", count, prefetched);
+    assert_eq!(count, prefetched);
+}
+
+#[ignore]
+#[test]
+fn test_fetch_page() {
+    let mut conn = connect("127.0.0.1:9042".to_string()).unwrap();
+
+    let statement = Statement::query("SELECT * FROM testing.users where super_key = 1 and user_id > ?")
+        .values((1i64,))
+        .consistency(Consistency::Quorum)
+        .page_size(10);
+
+    let mut cursor = None;
+    let mut count = 0;
+    loop {
+        let page = conn.fetch_page(statement.clone(), cursor.as_ref().map(|token: &String| token.as_str()), b"secret").unwrap();
+        count += page.rows.len();
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    println!("Cursor paging read {} rows", count);
+
+    let first = conn.fetch_page(statement.clone(), None, b"secret").unwrap();
+    let other = statement.values((500i64,));
+    assert!(conn.fetch_page(other, first.cursor.as_ref().map(|token| token.as_str()), b"secret").is_err());
+}
+
+#[ignore]
+#[test]
+fn test_tracing() {
+    let mut conn = connect("127.0.0.1:9042".to_string()).unwrap();
+
+    let statement = Statement::query("SELECT * FROM testing.users where super_key = 1")
+        .consistency(Consistency::One)
+        .tracing(true);
+
+    let envelope = conn.execute_envelope(statement).unwrap();
+    let session_id = envelope.tracing_id.expect("no tracing id on a traced response");
+
+    let trace = conn.trace(session_id).unwrap();
+    println!("Trace took {}us with {} events", trace.duration, trace.events.len());
+    assert!(!trace.events.is_empty());
+}
+
+#[ignore]
+#[test]
+fn test_pages() {
+    let mut conn = connect("127.0.0.1:9042".to_string()).unwrap();
+
+    let statement = Statement::query("SELECT * FROM testing.users where super_key = 1")
+        .consistency(Consistency::One)
+        .page_size(10);
+
+    let mut count = 0;
+    for page in conn.pages(statement) {
+        let page = page.unwrap();
+        count += page.rows().filter(|row| row.get_by_name::<Option<&str>>("user_id").unwrap().is_some()).count();
+    }
+    println!("Lazy paging read {} rows", count);
+}
+
+#[ignore]
+#[test]
+fn test_coalescing() {
+    let mut conn = connect("127.0.0.1:9042".to_string()).unwrap();
+    conn.set_coalescing(Some(Coalescing::default())).unwrap();
+
+    for i in 0..100 {
+        let statement = Statement::query("SELECT * FROM testing.users where super_key = ?")
+            .values((i,))
+            .consistency(Consistency::One);
+        conn.submit(statement).unwrap();
+    }
+    assert_eq!(conn.in_flight(), 100);
+
+    let mut streams = std::collections::HashSet::new();
+    while conn.in_flight() > 0 {
+        match conn.next_response().unwrap() {
+            (stream, Response::Result(_)) => assert!(streams.insert(stream)),
+            (_, response) => panic!("unexpected response {:?}", response)
+        }
+    }
+    assert_eq!(streams.len(), 100);
+    assert!(conn.next_response().is_err());
+}
+
+#[test]
+fn test_out_of_order_responses() {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    // answers STARTUP, then the next two requests in reverse order
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let read_header = |stream: &mut TcpStream| {
+            let mut header = [0u8; 9];
+            stream.read_exact(&mut header).unwrap();
+            let len = ((header[7] as usize) << 8) | header[8] as usize;
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body).unwrap();
+            header
+        };
+        let startup = read_header(&mut stream);
+        stream.write_all(&[0x84, 0, startup[2], startup[3], 0x02, 0, 0, 0, 0]).unwrap();
+        let first = read_header(&mut stream);
+        let second = read_header(&mut stream);
+        for header in [second, first].iter() {
+            stream.write_all(&[0x84, 0, header[2], header[3], 0x08, 0, 0, 0, 4, 0, 0, 0, 1]).unwrap();
+        }
+    });
+
+    let mut conn = connect_with_version(addr, ProtocolVersion::V4).unwrap();
+    let first = conn.submit(Statement::query("SELECT 1")).unwrap();
+    let second = conn.submit(Statement::query("SELECT 2")).unwrap();
+    assert!(first != second);
+
+    // the second answer arrives first and is kept until asked for
+    conn.response(first).unwrap();
+    assert_eq!(conn.in_flight(), 1);
+    let (stream, _) = conn.next_response().unwrap();
+    assert_eq!(stream, second);
+    assert_eq!(conn.in_flight(), 0);
+    assert!(conn.response(first).is_err());
+}
+
+#[test]
+fn test_warnings_kept() {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    // READY to STARTUP, then a void result warning "hi" to every request
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut first = true;
+        let mut header = [0u8; 9];
+        while stream.read_exact(&mut header).is_ok() {
+            let mut body = vec![0; ((header[7] as usize) << 8) | header[8] as usize];
+            stream.read_exact(&mut body).unwrap();
+            if first {
+                stream.write_all(&[0x84, 0, header[2], header[3], 0x02, 0, 0, 0, 0]).unwrap();
+                first = false;
+            } else {
+                stream.write_all(&[0x84, 0x08, header[2], header[3], 0x08, 0, 0, 0, 10,
+                    0, 1, 0, 2, b'h', b'i', 0, 0, 0, 1]).unwrap();
+            }
+        }
+    });
+
+    let mut conn = connect_with_version(addr, ProtocolVersion::V4).unwrap();
+    assert!(conn.last_warnings().is_empty());
+    conn.query("UPDATE t".to_string(), Consistency::One).unwrap();
+    assert_eq!(conn.last_warnings(), &["hi".to_string()][..]);
+
+    let page = conn.execute_borrowed(Statement::query("UPDATE t")).unwrap();
+    assert_eq!(page.warnings(), &["hi".to_string()][..]);
+}
+
+#[test]
+fn test_metadata_changed() {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    // READY to STARTUP, then to every request rows with a new metadata id 0xab and no rows
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut first = true;
+        let mut header = [0u8; 9];
+        while stream.read_exact(&mut header).is_ok() {
+            let mut body = vec![0; ((header[7] as usize) << 8) | header[8] as usize];
+            stream.read_exact(&mut body).unwrap();
+            if first {
+                stream.write_all(&[0x84, 0, header[2], header[3], 0x02, 0, 0, 0, 0]).unwrap();
+                first = false;
+            } else {
+                let rows = [0, 0, 0, 2, 0, 0, 0, 0x09, 0, 0, 0, 1, 0, 1, 0xab,
+                    0, 1, b'k', 0, 1, b't', 0, 1, b'v', 0, 9, 0, 0, 0, 0];
+                stream.write_all(&[0x84, 0, header[2], header[3], 0x08, 0, 0, 0, rows.len() as u8]).unwrap();
+                stream.write_all(&rows).unwrap();
+            }
+        }
+    });
+
+    let mut conn = connect_with_version(addr, ProtocolVersion::V4).unwrap();
+    conn.execute(Statement::prepared(vec!(1))).unwrap();
+    assert_eq!(conn.result_metadata_ids.get(&vec!(1)), Some(&vec!(0xab)));
+    assert_eq!(conn.prepared.get(&vec!(1)).map(|specs| specs.len()), Some(1));
+
+    conn.execute_borrowed(Statement::prepared(vec!(2))).unwrap();
+    assert_eq!(conn.result_metadata_ids.get(&vec!(2)), Some(&vec!(0xab)));
+    assert_eq!(conn.prepared.get(&vec!(2)).map(|specs| specs.len()), Some(1));
+}
+
+#[test]
+fn test_page_prefix() {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    // READY to STARTUP, then a void result with a tracing id, warning "hi" and payload {"k": [1]}
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut first = true;
+        let mut header = [0u8; 9];
+        while stream.read_exact(&mut header).is_ok() {
+            let mut body = vec![0; ((header[7] as usize) << 8) | header[8] as usize];
+            stream.read_exact(&mut body).unwrap();
+            if first {
+                stream.write_all(&[0x84, 0, header[2], header[3], 0x02, 0, 0, 0, 0]).unwrap();
+                first = false;
+            } else {
+                let mut result = vec!(7; 16);
+                result.extend_from_slice(&[0, 1, 0, 2, b'h', b'i']);
+                result.extend_from_slice(&[0, 1, 0, 1, b'k', 0, 0, 0, 1, 1]);
+                result.extend_from_slice(&[0, 0, 0, 1]);
+                stream.write_all(&[0x84, 0x0e, header[2], header[3], 0x08, 0, 0, 0, result.len() as u8]).unwrap();
+                stream.write_all(&result).unwrap();
+            }
+        }
+    });
+
+    let mut conn = connect_with_version(addr, ProtocolVersion::V4).unwrap();
+    let page = conn.execute_borrowed(Statement::query("UPDATE t")).unwrap();
+    assert_eq!(page.tracing_id(), Uuid::from_bytes(&[7; 16]).ok());
+    assert_eq!(page.warnings(), &["hi".to_string()][..]);
+    assert_eq!(page.custom_payload().get("k"), Some(&vec!(1)));
+}
+
+#[test]
+fn test_abandoned_page() {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+
+    // A rows result with one int column `v` holding `value`, and paging state 7 if `more`.
+    fn page(value: u8, more: bool) -> Vec<u8> {
+        let mut body = vec!(0, 0, 0, 2, 0, 0, 0, if more { 0x03 } else { 0x01 }, 0, 0, 0, 1);
+        if more {
+            body.extend_from_slice(&[0, 0, 0, 1, 7]);
+        }
+        body.extend_from_slice(&[0, 1, b'k', 0, 1, b't', 0, 1, b'v', 0, 9]);
+        body.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, value]);
+        body
+    }
+
+    // READY to STARTUP and the first page, then the prefetched second page only
+    // once the next request came in, which would deadlock a reader waiting for it
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let read_header = |stream: &mut ::std::net::TcpStream| {
+            let mut header = [0u8; 9];
+            stream.read_exact(&mut header).unwrap();
+            let mut body = vec![0; ((header[7] as usize) << 8) | header[8] as usize];
+            stream.read_exact(&mut body).unwrap();
+            header
+        };
+        let respond = |stream: &mut ::std::net::TcpStream, header: [u8; 9], opcode: u8, body: &[u8]| {
+            stream.write_all(&[0x84, 0, header[2], header[3], opcode, 0, 0, 0, body.len() as u8]).unwrap();
+            stream.write_all(body).unwrap();
+        };
+        let startup = read_header(&mut stream);
+        respond(&mut stream, startup, 0x02, &[]);
+        let first = read_header(&mut stream);
+        respond(&mut stream, first, 0x08, &page(1, true));
+        let second = read_header(&mut stream);
+        let next = read_header(&mut stream);
+        respond(&mut stream, second, 0x08, &page(2, false));
+        respond(&mut stream, next, 0x08, &[0, 0, 0, 1]);
+    });
+
+    let mut conn = connect_with_version(addr, ProtocolVersion::V4).unwrap();
+    {
+        let mut rows = conn.iter(Statement::query("SELECT v FROM k.t").page_size(1)).prefetch(true);
+        assert_eq!(rows.next().unwrap().unwrap().columns, vec!(Column::Int(1)));
+    }
+    assert_eq!(conn.in_flight(), 0);
+    match conn.query("UPDATE k.t".to_string(), Consistency::One).unwrap() {
+        Response::Result(ResultBody::Void) => {}
+        response => panic!("unexpected response {:?}", response)
+    }
+}
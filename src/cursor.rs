@@ -0,0 +1,280 @@
+//! Opaque paging cursors for stateless APIs.
+//!
+//! A cursor token is the URL-safe base64 encoding of a version byte, an
+//! HMAC-SHA256 of the statement, its values and the server's paging state, and
+//! the paging state itself. The MAC is keyed with a secret only the application
+//! knows, so clients can neither forge a paging state nor replay a token
+//! against a different query.
+
+use std::fmt;
+use std::io::{
+    Result,
+    Error,
+    ErrorKind
+};
+
+use byteorder::{
+    BigEndian,
+    ByteOrder
+};
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+use shared::{
+    Column,
+    Row
+};
+
+use statement::{
+    Statement,
+    Target,
+    Values
+};
+
+use writing::{
+    write_values,
+    write_named_values
+};
+
+use mac::{
+    hmac_sha256,
+    verify
+};
+
+static CURSOR_VERSION: u8 = 0x02;
+
+const MAC_LEN: usize = 32;
+
+#[derive(Clone, PartialEq)]
+pub struct Cursor {
+    pub mac: Vec<u8>,
+    pub paging_state: Vec<u8>,
+    secret: Vec<u8>
+}
+
+/// One page of rows and the token to fetch the next one, if there is one.
+#[derive(Debug)]
+pub struct Page {
+    pub rows: Vec<Row>,
+    pub cursor: Option<String>,
+    pub warnings: Vec<String>
+}
+
+impl Cursor {
+    /// Signs `paging_state` for `statement` with `secret`, which must not be empty.
+    pub fn new(statement: &Statement, paging_state: Vec<u8>, secret: &[u8]) -> Result<Cursor> {
+        try!(check_secret(secret));
+        Ok(Cursor {
+            mac: try!(sign(statement, &paging_state, secret)),
+            paging_state: paging_state,
+            secret: secret.to_vec()
+        })
+    }
+
+    pub fn to_token(&self) -> String {
+        let mut bytes = vec!(CURSOR_VERSION);
+        bytes.extend_from_slice(&self.mac);
+        bytes.extend_from_slice(&self.paging_state);
+        URL_SAFE_NO_PAD.encode(&bytes)
+    }
+
+    /// Reads a token issued with the same `secret`. It is checked by `resume`.
+    pub fn from_token(token: &str, secret: &[u8]) -> Result<Cursor> {
+        try!(check_secret(secret));
+        let bytes = match URL_SAFE_NO_PAD.decode(token) {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, "Malformed paging cursor"))
+        };
+        if bytes.len() < 1 + MAC_LEN || bytes[0] != CURSOR_VERSION {
+            return Err(Error::new(ErrorKind::InvalidInput, "Malformed paging cursor"));
+        }
+        Ok(Cursor {
+            mac: bytes[1..1 + MAC_LEN].to_vec(),
+            paging_state: bytes[1 + MAC_LEN..].to_vec(),
+            secret: secret.to_vec()
+        })
+    }
+
+    /// Returns the paging state if the cursor was signed for this statement.
+    pub fn resume(self, statement: &Statement) -> Result<Vec<u8>> {
+        let expected = try!(sign(statement, &self.paging_state, &self.secret));
+        if !verify(&expected, &self.mac) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Paging cursor was not issued for this statement"));
+        }
+        Ok(self.paging_state)
+    }
+}
+
+// the secret is left out
+impl fmt::Debug for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cursor")
+            .field("mac", &self.mac)
+            .field("paging_state", &self.paging_state)
+            .finish()
+    }
+}
+
+fn check_secret(secret: &[u8]) -> Result<()> {
+    if secret.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Paging cursors need a secret to be signed with"));
+    }
+    Ok(())
+}
+
+fn sign(statement: &Statement, paging_state: &[u8], secret: &[u8]) -> Result<Vec<u8>> {
+    let statement = try!(statement_bytes(statement));
+    let mut message = vec![0; 4];
+    BigEndian::write_u32(&mut message, statement.len() as u32);
+    message.extend_from_slice(&statement);
+    message.extend_from_slice(paging_state);
+    Ok(hmac_sha256(secret, &message).to_vec())
+}
+
+/// The statement text (or prepared id) and its values as they are encoded on
+/// the wire, so they are stable across processes and builds. Set elements, map
+/// entries and named values are in a canonical order.
+fn statement_bytes(statement: &Statement) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    match statement.target {
+        Target::Query(ref query) => {
+            buf.push(0x01);
+            buf.extend_from_slice(query.as_bytes());
+        }
+        Target::Prepared(ref id) => {
+            buf.push(0x02);
+            buf.extend_from_slice(id);
+        }
+    }
+    match statement.values {
+        Values::Positional(ref values) => {
+            let mut canonical_values = Vec::with_capacity(values.len());
+            for value in values.iter() {
+                canonical_values.push(try!(canonical(value)));
+            }
+            try!(write_values(&mut buf, &canonical_values));
+        }
+        Values::Named(ref named_values) => {
+            let mut canonical_values = Vec::with_capacity(named_values.len());
+            for (name, value) in named_values.iter() {
+                canonical_values.push((name.clone(), try!(canonical(value))));
+            }
+            canonical_values.sort_by(|a, b| a.0.cmp(&b.0));
+            try!(write_named_values(&mut buf, &canonical_values));
+        }
+        Values::None => {}
+    }
+    Ok(buf)
+}
+
+// Sets and maps built from a HashSet or HashMap iterate in a different order in
+// every process, so their elements are sorted by their encoding.
+fn canonical(column: &Column) -> Result<Column> {
+    let canonical_column = match *column {
+        Column::Set(ref values) => {
+            let mut keyed = Vec::with_capacity(values.len());
+            for value in values.iter() {
+                let value = try!(canonical(value));
+                keyed.push((try!(encoded(&value)), value));
+            }
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            Column::Set(keyed.into_iter().map(|(_, value)| value).collect())
+        }
+        Column::Map(ref entries) => {
+            let mut keyed = Vec::with_capacity(entries.len());
+            for (key, value) in entries.iter() {
+                let key = try!(canonical(key));
+                keyed.push((try!(encoded(&key)), (key, try!(canonical(value)))));
+            }
+            keyed.sort_by(|a, b| a.0.cmp(&b.0));
+            Column::Map(keyed.into_iter().map(|(_, entry)| entry).collect())
+        }
+        Column::List(ref values) => Column::List(try!(values.iter().map(canonical).collect())),
+        Column::Tuple(ref values) => Column::Tuple(try!(values.iter().map(canonical).collect())),
+        Column::Udt(ref fields) => {
+            let mut canonical_fields = Vec::with_capacity(fields.len());
+            for (name, value) in fields.iter() {
+                canonical_fields.push((name.clone(), try!(canonical(value))));
+            }
+            Column::Udt(canonical_fields)
+        }
+        ref other => other.clone()
+    };
+    Ok(canonical_column)
+}
+
+fn encoded(column: &Column) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    try!(write_values(&mut buf, &vec![column.clone()]));
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SECRET: &'static [u8] = b"not so secret";
+
+    #[test]
+    fn test_token_roundtrip() {
+        let statement = Statement::query("SELECT * FROM testing.users WHERE super_key = ?").values((1i64,));
+        let cursor = Cursor::new(&statement, vec![0, 1, 2, 250, 251, 255], SECRET).unwrap();
+        let token = cursor.to_token();
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(Cursor::from_token(&token, SECRET).unwrap().resume(&statement).unwrap(), vec![0, 1, 2, 250, 251, 255]);
+    }
+
+    #[test]
+    fn test_replay_rejected() {
+        let statement = Statement::query("SELECT * FROM testing.users WHERE super_key = ?").values((1i64,));
+        let other = statement.clone().values((2i64,));
+        let token = Cursor::new(&statement, vec![1, 2, 3], SECRET).unwrap().to_token();
+        let err = Cursor::from_token(&token, SECRET).unwrap().resume(&other).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(Cursor::from_token("not a cursor!", SECRET).is_err());
+        assert!(Cursor::new(&statement, vec![1, 2, 3], b"").is_err());
+    }
+
+    #[test]
+    fn test_forgery_rejected() {
+        let statement = Statement::query("SELECT * FROM testing.users WHERE super_key = ?").values((1i64,));
+        let token = Cursor::new(&statement, vec![1, 2, 3], SECRET).unwrap().to_token();
+
+        // a token signed with another secret
+        assert!(Cursor::from_token(&token, b"another secret").unwrap().resume(&statement).is_err());
+
+        // a paging state swapped in under a valid MAC
+        let mut cursor = Cursor::from_token(&token, SECRET).unwrap();
+        cursor.paging_state = vec![1, 2, 4];
+        let forged = Cursor::from_token(&cursor.to_token(), SECRET).unwrap();
+        assert!(forged.resume(&statement).is_err());
+    }
+
+    #[test]
+    fn test_canonical_order() {
+        let a = Column::String("a".to_string());
+        let b = Column::String("b".to_string());
+        let one = Column::Int(1);
+        let two = Column::Int(2);
+
+        let statement = Statement::query("SELECT * FROM t WHERE k IN ? AND m = ?").values((
+            Column::Set(vec![a.clone(), b.clone()]),
+            Column::Map(vec![(a.clone(), one.clone()), (b.clone(), two.clone())])
+        ));
+        let reordered = Statement::query("SELECT * FROM t WHERE k IN ? AND m = ?").values((
+            Column::Set(vec![b.clone(), a.clone()]),
+            Column::Map(vec![(b.clone(), two.clone()), (a.clone(), one.clone())])
+        ));
+        assert_eq!(statement_bytes(&statement).unwrap(), statement_bytes(&reordered).unwrap());
+
+        let named = Statement::query("U").named_values(vec![("x".to_string(), one.clone()), ("y".to_string(), two.clone())]);
+        let renamed = Statement::query("U").named_values(vec![("y".to_string(), two.clone()), ("x".to_string(), one.clone())]);
+        assert_eq!(statement_bytes(&named).unwrap(), statement_bytes(&renamed).unwrap());
+
+        // lists keep their order
+        let list = Statement::query("U").values((Column::List(vec![a.clone(), b.clone()]),));
+        let reversed = Statement::query("U").values((Column::List(vec![b, a]),));
+        assert!(statement_bytes(&list).unwrap() != statement_bytes(&reversed).unwrap());
+    }
+}
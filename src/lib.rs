@@ -7,14 +7,43 @@ extern crate tokio_core;
 extern crate bufstream;
 extern crate byteorder;
 extern crate uuid;
+extern crate base64;
+extern crate crc32fast;
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+#[cfg(feature = "chrono")]
+extern crate chrono;
+#[cfg(feature = "derive")]
+extern crate rustcql_derive;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
 
 pub mod connection;
+pub mod coalescing;
 pub mod shared;
+pub mod mapping;
+pub mod statement;
+pub mod paging;
+pub mod async_connection;
+pub mod cursor;
+pub mod timestamp;
+pub mod lwt;
+pub mod borrowed;
+pub mod tracing;
+pub mod pool;
+pub mod cluster;
+#[cfg(feature = "serde")]
+pub mod serialization;
+
+pub mod checksum;
+pub mod mac;
 
 mod reading {
   pub mod reader;
+  pub mod segment;
   mod spec;
-  mod value;
+  pub mod value;
 }
 
 pub mod writing;
@@ -29,5 +58,20 @@ use std::io::{
 
 
 pub use connection::Connection;
-pub use connection::connect;
+pub use connection::{connect, connect_with_version, connect_with_compression, connect_with_timeout, Coalescing};
+pub use shared::{ProtocolVersion, Compression};
+pub use statement::{Statement, Batch};
+pub use paging::{RowIterator, PageIterator};
+pub use async_connection::{AsyncConnection, RowStream, connect_async};
+pub use cursor::{Cursor, Page};
+pub use lwt::LwtResult;
+pub use borrowed::{RowsPage, RowRef, FromCqlRef};
+pub use tracing::{Trace, TraceEvent};
+pub use pool::{Pool, PoolConfig, PooledConnection};
+pub use cluster::{Cluster, Session, Node};
+pub use timestamp::{TimestampGenerator, MonotonicTimestampGenerator};
+pub use mapping::{FromCql, FromRow, ToCql, IntoValues, IntoNamedValues, MappingError};
+
+#[cfg(feature = "derive")]
+pub use rustcql_derive::*;
 
@@ -0,0 +1,129 @@
+//! Results of lightweight transactions.
+//!
+//! A conditional statement (`INSERT ... IF NOT EXISTS`, `UPDATE ... IF col = ?`)
+//! or a batch of them answers with rows carrying an `[applied]` column. When
+//! the condition failed, the other columns hold the values currently stored.
+
+use std::io::{
+    Result,
+    Error,
+    ErrorKind
+};
+
+use std::sync::Arc;
+use std::result;
+
+use shared::{
+    Response,
+    ResultBody,
+    Row
+};
+
+use mapping::{
+    FromRow,
+    MappingError
+};
+
+pub static APPLIED_COLUMN: &'static str = "[applied]";
+
+#[derive(Debug, Clone)]
+pub struct LwtResult {
+    pub applied: bool,
+    /// The rows that prevented the update, without the `[applied]` column.
+    /// Empty when the update was applied.
+    pub current: Vec<Row>
+}
+
+impl LwtResult {
+    pub fn from_response(response: Response) -> Result<LwtResult> {
+        match response {
+            Response::Result(ResultBody::Rows(rows, _)) => LwtResult::from_rows(rows),
+            Response::Error(code, message) =>
+                Err(Error::new(ErrorKind::Other, format!("Error {:#x} in conditional update: {}", code, message))),
+            response =>
+                Err(Error::new(ErrorKind::InvalidData, format!("Not a conditional update result: {:?}", response)))
+        }
+    }
+
+    pub fn from_rows(rows: Vec<Row>) -> Result<LwtResult> {
+        let index = match rows.first().and_then(|row| row.index_of(APPLIED_COLUMN)) {
+            Some(index) => index,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Result has no [applied] column"))
+        };
+        // a batch answers with one row per conditional statement, all with the same outcome
+        let applied: bool = try!(rows[0].get_as(index));
+
+        let current = if applied {
+            vec!()
+        } else {
+            let mut specs = (*rows[0].specs).clone();
+            specs.remove(index);
+            let specs = Arc::new(specs);
+            rows.into_iter().map(|mut row| {
+                row.columns.remove(index);
+                Row { specs: specs.clone(), columns: row.columns }
+            }).collect()
+        };
+
+        Ok(LwtResult {
+            applied: applied,
+            current: current
+        })
+    }
+
+    /// The first conflicting row as typed values, `None` if the update was applied.
+    pub fn current_as<T: FromRow>(&self) -> Option<result::Result<T, MappingError>> {
+        self.current.first().map(T::from_row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use shared::{
+        Column,
+        ColumnSpec,
+        ColumnType,
+        CollectionSpec,
+        Row
+    };
+
+    use super::*;
+
+    fn row(columns: Vec<(&str, ColumnType, Column)>) -> Row {
+        let specs = columns.iter().map(|&(name, data_type, _)| ColumnSpec {
+            name: name.to_string(),
+            data_type: data_type,
+            collection_spec: CollectionSpec::None
+        }).collect();
+        Row {
+            specs: Arc::new(specs),
+            columns: columns.into_iter().map(|(_, _, column)| column).collect()
+        }
+    }
+
+    #[test]
+    fn test_applied() {
+        let result = LwtResult::from_rows(vec![
+            row(vec![("[applied]", ColumnType::Boolean, Column::Boolean(true))])
+        ]).unwrap();
+        assert!(result.applied);
+        assert!(result.current.is_empty());
+        assert!(result.current_as::<(String,)>().is_none());
+    }
+
+    #[test]
+    fn test_not_applied() {
+        let result = LwtResult::from_rows(vec![
+            row(vec![
+                ("[applied]", ColumnType::Boolean, Column::Boolean(false)),
+                ("user_id", ColumnType::Varchar, Column::String("jsmith".to_string())),
+                ("age", ColumnType::Int, Column::Int(42))
+            ])
+        ]).unwrap();
+        assert!(!result.applied);
+        assert_eq!(result.current[0].index_of("[applied]"), None);
+        assert_eq!(result.current_as::<(String, i32)>().unwrap().unwrap(), ("jsmith".to_string(), 42));
+    }
+}
@@ -0,0 +1,628 @@
+use std::io;
+use std::fmt;
+use std::error;
+use std::result;
+use std::hash::Hash;
+use std::net::{
+	IpAddr,
+	Ipv4Addr,
+	Ipv6Addr
+};
+use std::collections::{
+	HashMap,
+	HashSet
+};
+
+use uuid::Uuid;
+
+#[cfg(feature = "chrono")]
+use chrono::{
+	DateTime,
+	NaiveDateTime,
+	TimeZone,
+	Utc
+};
+
+use shared::{
+	Column,
+	Row
+};
+
+/// Error returned when a decoded value cannot be turned into the requested Rust type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MappingError {
+	/// The value has a different CQL type than the one asked for.
+	TypeMismatch {
+		column: Option<String>,
+		expected: &'static str,
+		found: &'static str
+	},
+	/// The value is null but the target type is not an `Option`.
+	UnexpectedNull(Option<String>),
+	/// The row has no column with this name (or index).
+	MissingColumn(String),
+	/// The row has a different number of columns than the target type.
+	ColumnCount {
+		expected: usize,
+		found: usize
+	},
+	/// Any other conversion failure, e.g. reported by serde.
+	Message(String)
+}
+
+impl MappingError {
+	pub fn mismatch(expected: &'static str, found: &Column) -> MappingError {
+		match *found {
+			Column::None | Column::Null | Column::Unset => MappingError::UnexpectedNull(None),
+			_ => MappingError::TypeMismatch {
+				column: None,
+				expected: expected,
+				found: found.type_name()
+			}
+		}
+	}
+
+	/// Attaches the column name to an error produced by `FromCql`.
+	pub fn in_column(self, name: &str) -> MappingError {
+		match self {
+			MappingError::TypeMismatch { column: None, expected, found } =>
+				MappingError::TypeMismatch { column: Some(name.to_string()), expected: expected, found: found },
+			MappingError::UnexpectedNull(None) =>
+				MappingError::UnexpectedNull(Some(name.to_string())),
+			e => e
+		}
+	}
+}
+
+impl fmt::Display for MappingError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			MappingError::TypeMismatch { column: Some(ref name), expected, found } =>
+				write!(f, "column \"{}\": expected {}, found {}", name, expected, found),
+			MappingError::TypeMismatch { column: None, expected, found } =>
+				write!(f, "expected {}, found {}", expected, found),
+			MappingError::UnexpectedNull(Some(ref name)) =>
+				write!(f, "column \"{}\": unexpected null", name),
+			MappingError::UnexpectedNull(None) =>
+				write!(f, "unexpected null"),
+			MappingError::MissingColumn(ref name) =>
+				write!(f, "no column \"{}\" in row", name),
+			MappingError::ColumnCount { expected, found } =>
+				write!(f, "expected {} columns, found {}", expected, found),
+			MappingError::Message(ref msg) =>
+				write!(f, "{}", msg)
+		}
+	}
+}
+
+impl error::Error for MappingError {}
+
+impl From<MappingError> for io::Error {
+	fn from(e: MappingError) -> io::Error {
+		io::Error::new(io::ErrorKind::InvalidData, e)
+	}
+}
+
+/// Conversion from a decoded `Column` into a Rust value.
+pub trait FromCql: Sized {
+	fn from_cql(column: &Column) -> result::Result<Self, MappingError>;
+}
+
+/// Conversion from a whole `Row` into a struct or tuple.
+pub trait FromRow: Sized {
+	fn from_row(row: &Row) -> result::Result<Self, MappingError>;
+}
+
+/// Conversion from a Rust value into a `Column` that can be bound to a statement.
+pub trait ToCql {
+	fn to_cql(&self) -> Column;
+}
+
+/// A list of positional bind values: tuples, slices and vectors of `ToCql` values.
+pub trait IntoValues {
+	fn into_values(self) -> Vec<Column>;
+}
+
+/// A list of bind values addressed by name, for `prm_query_with_names`.
+pub trait IntoNamedValues {
+	fn into_named_values(self) -> Vec<(String, Column)>;
+}
+
+/// Looks up a field of a decoded user defined type, used by `#[derive(Udt)]`.
+pub fn udt_field<'a>(fields: &'a [(String, Column)], name: &str) -> Option<&'a Column> {
+	fields.iter().find(|&&(ref field, _)| field == name).map(|&(_, ref column)| column)
+}
+
+impl<T: FromCql> FromCql for Option<T> {
+	fn from_cql(column: &Column) -> result::Result<Option<T>, MappingError> {
+		match *column {
+			Column::None | Column::Null | Column::Unset => Ok(None),
+			_ => T::from_cql(column).map(Some)
+		}
+	}
+}
+
+impl FromCql for Column {
+	fn from_cql(column: &Column) -> result::Result<Column, MappingError> {
+		Ok(column.clone())
+	}
+}
+
+impl FromCql for bool {
+	fn from_cql(column: &Column) -> result::Result<bool, MappingError> {
+		match *column {
+			Column::Boolean(val) => Ok(val),
+			_ => Err(MappingError::mismatch("boolean", column))
+		}
+	}
+}
+
+impl FromCql for i8 {
+	fn from_cql(column: &Column) -> result::Result<i8, MappingError> {
+		match *column {
+			Column::Tinyint(val) => Ok(val),
+			_ => Err(MappingError::mismatch("tinyint", column))
+		}
+	}
+}
+
+impl FromCql for i16 {
+	fn from_cql(column: &Column) -> result::Result<i16, MappingError> {
+		match *column {
+			Column::Smallint(val) => Ok(val),
+			Column::Tinyint(val) => Ok(val as i16),
+			_ => Err(MappingError::mismatch("smallint", column))
+		}
+	}
+}
+
+impl FromCql for i32 {
+	fn from_cql(column: &Column) -> result::Result<i32, MappingError> {
+		match *column {
+			Column::Int(val) => Ok(val),
+			Column::Smallint(val) => Ok(val as i32),
+			Column::Tinyint(val) => Ok(val as i32),
+			_ => Err(MappingError::mismatch("int", column))
+		}
+	}
+}
+
+impl FromCql for i64 {
+	fn from_cql(column: &Column) -> result::Result<i64, MappingError> {
+		match *column {
+			Column::Bigint(val) | Column::Timestamp(val) => Ok(val),
+			Column::Int(val) => Ok(val as i64),
+			Column::Smallint(val) => Ok(val as i64),
+			Column::Tinyint(val) => Ok(val as i64),
+			_ => Err(MappingError::mismatch("bigint", column))
+		}
+	}
+}
+
+impl FromCql for f32 {
+	fn from_cql(column: &Column) -> result::Result<f32, MappingError> {
+		match *column {
+			Column::Float(val) => Ok(val),
+			_ => Err(MappingError::mismatch("float", column))
+		}
+	}
+}
+
+impl FromCql for f64 {
+	fn from_cql(column: &Column) -> result::Result<f64, MappingError> {
+		match *column {
+			Column::Double(val) => Ok(val),
+			Column::Float(val) => Ok(val as f64),
+			_ => Err(MappingError::mismatch("double", column))
+		}
+	}
+}
+
+impl FromCql for String {
+	fn from_cql(column: &Column) -> result::Result<String, MappingError> {
+		match *column {
+			Column::String(ref val) => Ok(val.clone()),
+			_ => Err(MappingError::mismatch("string", column))
+		}
+	}
+}
+
+impl FromCql for Vec<u8> {
+	fn from_cql(column: &Column) -> result::Result<Vec<u8>, MappingError> {
+		match *column {
+			Column::Blob(ref val) => Ok(val.clone()),
+			_ => Err(MappingError::mismatch("blob", column))
+		}
+	}
+}
+
+impl FromCql for Uuid {
+	fn from_cql(column: &Column) -> result::Result<Uuid, MappingError> {
+		match *column {
+			Column::String(ref val) => Uuid::parse_str(val).map_err(|_| MappingError::mismatch("uuid", column)),
+			Column::Blob(ref val) => Uuid::from_bytes(val).map_err(|_| MappingError::mismatch("uuid", column)),
+			_ => Err(MappingError::mismatch("uuid", column))
+		}
+	}
+}
+
+impl FromCql for IpAddr {
+	fn from_cql(column: &Column) -> result::Result<IpAddr, MappingError> {
+		match *column {
+			Column::Blob(ref val) if val.len() == 4 =>
+				Ok(IpAddr::V4(Ipv4Addr::new(val[0], val[1], val[2], val[3]))),
+			Column::Blob(ref val) if val.len() == 16 => {
+				let mut octets = [0u8; 16];
+				octets.copy_from_slice(val);
+				Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+			}
+			_ => Err(MappingError::mismatch("inet", column))
+		}
+	}
+}
+
+impl<T: FromCql> FromCql for Vec<T> {
+	fn from_cql(column: &Column) -> result::Result<Vec<T>, MappingError> {
+		match *column {
+			Column::List(ref vals) | Column::Set(ref vals) => vals.iter().map(T::from_cql).collect(),
+			_ => Err(MappingError::mismatch("list", column))
+		}
+	}
+}
+
+impl<T: FromCql + Eq + Hash> FromCql for HashSet<T> {
+	fn from_cql(column: &Column) -> result::Result<HashSet<T>, MappingError> {
+		match *column {
+			Column::Set(ref vals) | Column::List(ref vals) => vals.iter().map(T::from_cql).collect(),
+			_ => Err(MappingError::mismatch("set", column))
+		}
+	}
+}
+
+impl<K: FromCql + Eq + Hash, V: FromCql> FromCql for HashMap<K, V> {
+	fn from_cql(column: &Column) -> result::Result<HashMap<K, V>, MappingError> {
+		match *column {
+			Column::Map(ref pairs) => {
+				let mut map = HashMap::with_capacity(pairs.len());
+				for &(ref key, ref val) in pairs.iter() {
+					map.insert(try!(K::from_cql(key)), try!(V::from_cql(val)));
+				}
+				Ok(map)
+			}
+			_ => Err(MappingError::mismatch("map", column))
+		}
+	}
+}
+
+/// Timestamps are milliseconds since the epoch.
+#[cfg(feature = "chrono")]
+impl FromCql for DateTime<Utc> {
+	fn from_cql(column: &Column) -> result::Result<DateTime<Utc>, MappingError> {
+		match *column {
+			Column::Timestamp(millis) => {
+				let secs = millis.div_euclid(1000);
+				let nanos = (millis.rem_euclid(1000) * 1_000_000) as u32;
+				Utc.timestamp_opt(secs, nanos).single().ok_or_else(|| MappingError::mismatch("timestamp", column))
+			}
+			_ => Err(MappingError::mismatch("timestamp", column))
+		}
+	}
+}
+
+#[cfg(feature = "chrono")]
+impl FromCql for NaiveDateTime {
+	fn from_cql(column: &Column) -> result::Result<NaiveDateTime, MappingError> {
+		DateTime::<Utc>::from_cql(column).map(|date| date.naive_utc())
+	}
+}
+
+/// `None` is bound as an explicit null.
+impl<T: ToCql> ToCql for Option<T> {
+	fn to_cql(&self) -> Column {
+		match *self {
+			Some(ref val) => val.to_cql(),
+			None => Column::Null
+		}
+	}
+}
+
+impl<'a, T: ToCql + ?Sized> ToCql for &'a T {
+	fn to_cql(&self) -> Column {
+		(**self).to_cql()
+	}
+}
+
+impl ToCql for Column {
+	fn to_cql(&self) -> Column {
+		self.clone()
+	}
+}
+
+impl ToCql for bool {
+	fn to_cql(&self) -> Column {
+		Column::Boolean(*self)
+	}
+}
+
+impl ToCql for i8 {
+	fn to_cql(&self) -> Column {
+		Column::Tinyint(*self)
+	}
+}
+
+impl ToCql for i16 {
+	fn to_cql(&self) -> Column {
+		Column::Smallint(*self)
+	}
+}
+
+impl ToCql for i32 {
+	fn to_cql(&self) -> Column {
+		Column::Int(*self)
+	}
+}
+
+impl ToCql for i64 {
+	fn to_cql(&self) -> Column {
+		Column::Bigint(*self)
+	}
+}
+
+impl ToCql for f32 {
+	fn to_cql(&self) -> Column {
+		Column::Float(*self)
+	}
+}
+
+impl ToCql for f64 {
+	fn to_cql(&self) -> Column {
+		Column::Double(*self)
+	}
+}
+
+impl ToCql for str {
+	fn to_cql(&self) -> Column {
+		Column::String(self.to_string())
+	}
+}
+
+impl ToCql for String {
+	fn to_cql(&self) -> Column {
+		Column::String(self.clone())
+	}
+}
+
+impl ToCql for Vec<u8> {
+	fn to_cql(&self) -> Column {
+		Column::Blob(self.clone())
+	}
+}
+
+impl ToCql for Uuid {
+	fn to_cql(&self) -> Column {
+		Column::Blob(self.as_bytes().to_vec())
+	}
+}
+
+impl ToCql for IpAddr {
+	fn to_cql(&self) -> Column {
+		match *self {
+			IpAddr::V4(ref addr) => Column::Blob(addr.octets().to_vec()),
+			IpAddr::V6(ref addr) => Column::Blob(addr.octets().to_vec())
+		}
+	}
+}
+
+impl<T: ToCql> ToCql for Vec<T> {
+	fn to_cql(&self) -> Column {
+		Column::List(self.iter().map(ToCql::to_cql).collect())
+	}
+}
+
+impl<T: ToCql + Eq + Hash> ToCql for HashSet<T> {
+	fn to_cql(&self) -> Column {
+		Column::Set(self.iter().map(ToCql::to_cql).collect())
+	}
+}
+
+impl<K: ToCql + Eq + Hash, V: ToCql> ToCql for HashMap<K, V> {
+	fn to_cql(&self) -> Column {
+		Column::Map(self.iter().map(|(key, val)| (key.to_cql(), val.to_cql())).collect())
+	}
+}
+
+#[cfg(feature = "chrono")]
+impl ToCql for DateTime<Utc> {
+	fn to_cql(&self) -> Column {
+		Column::Timestamp(self.timestamp() * 1000 + self.timestamp_subsec_millis() as i64)
+	}
+}
+
+impl IntoValues for () {
+	fn into_values(self) -> Vec<Column> {
+		vec!()
+	}
+}
+
+impl IntoValues for Vec<Column> {
+	fn into_values(self) -> Vec<Column> {
+		self
+	}
+}
+
+impl IntoNamedValues for Vec<(String, Column)> {
+	fn into_named_values(self) -> Vec<(String, Column)> {
+		self
+	}
+}
+
+impl<'a, T: ToCql> IntoNamedValues for &'a [(&'a str, T)] {
+	fn into_named_values(self) -> Vec<(String, Column)> {
+		self.iter().map(|&(name, ref val)| (name.to_string(), val.to_cql())).collect()
+	}
+}
+
+impl<K: Into<String> + Eq + Hash, T: ToCql> IntoNamedValues for HashMap<K, T> {
+	fn into_named_values(self) -> Vec<(String, Column)> {
+		self.into_iter().map(|(name, val)| (name.into(), val.to_cql())).collect()
+	}
+}
+
+impl<'a, T: ToCql> IntoValues for &'a [T] {
+	fn into_values(self) -> Vec<Column> {
+		self.iter().map(ToCql::to_cql).collect()
+	}
+}
+
+impl<'a, T: ToCql> IntoValues for &'a Vec<T> {
+	fn into_values(self) -> Vec<Column> {
+		self.iter().map(ToCql::to_cql).collect()
+	}
+}
+
+impl FromRow for Row {
+	fn from_row(row: &Row) -> result::Result<Row, MappingError> {
+		Ok(row.clone())
+	}
+}
+
+macro_rules! count {
+	() => (0usize);
+	($head:ident $($tail:ident)*) => (1usize + count!($($tail)*));
+}
+
+macro_rules! tuple_impls {
+	($($name:ident: $index:tt),+) => {
+		impl<$($name: FromCql),+> FromCql for ($($name,)+) {
+			fn from_cql(column: &Column) -> result::Result<($($name,)+), MappingError> {
+				match *column {
+					Column::Tuple(ref vals) if vals.len() == count!($($name)+) =>
+						Ok(($(try!($name::from_cql(&vals[$index]))),+,)),
+					_ => Err(MappingError::mismatch("tuple", column))
+				}
+			}
+		}
+
+		impl<$($name: ToCql),+> ToCql for ($($name,)+) {
+			fn to_cql(&self) -> Column {
+				Column::Tuple(vec![$(self.$index.to_cql()),+])
+			}
+		}
+
+		impl<$($name: ToCql),+> IntoValues for ($($name,)+) {
+			fn into_values(self) -> Vec<Column> {
+				vec![$(self.$index.to_cql()),+]
+			}
+		}
+
+		impl<$($name: FromCql),+> FromRow for ($($name,)+) {
+			fn from_row(row: &Row) -> result::Result<($($name,)+), MappingError> {
+				let expected = count!($($name)+);
+				if row.len() != expected {
+					return Err(MappingError::ColumnCount { expected: expected, found: row.len() });
+				}
+				Ok(($(try!(row.get_as::<$name>($index))),+,))
+			}
+		}
+	}
+}
+
+tuple_impls!(A: 0);
+tuple_impls!(A: 0, B: 1);
+tuple_impls!(A: 0, B: 1, C: 2);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10);
+tuple_impls!(A: 0, B: 1, C: 2, D: 3, E: 4, F: 5, G: 6, H: 7, I: 8, J: 9, K: 10, L: 11);
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::collections::HashMap;
+
+	use shared::{
+		Column,
+		ColumnSpec,
+		ColumnType,
+		CollectionSpec,
+		Row
+	};
+
+	use super::*;
+
+	fn row(columns: Vec<(&str, ColumnType, Column)>) -> Row {
+		let specs = columns.iter().map(|&(name, data_type, _)| ColumnSpec {
+			name: name.to_string(),
+			data_type: data_type,
+			collection_spec: CollectionSpec::None
+		}).collect();
+		Row {
+			specs: Arc::new(specs),
+			columns: columns.into_iter().map(|(_, _, column)| column).collect()
+		}
+	}
+
+	#[test]
+	fn test_from_row_tuple() {
+		let r = row(vec![
+			("user_id", ColumnType::Varchar, Column::String("jsmith".to_string())),
+			("age", ColumnType::Int, Column::Int(42)),
+			("height", ColumnType::Float, Column::None)
+		]);
+		let (user_id, age, height): (String, i32, Option<f32>) = FromRow::from_row(&r).unwrap();
+		assert_eq!(user_id, "jsmith");
+		assert_eq!(age, 42);
+		assert_eq!(height, None);
+	}
+
+	#[test]
+	fn test_mismatch_names_column() {
+		let r = row(vec![("age", ColumnType::Varchar, Column::String("42".to_string()))]);
+		let err = r.get_by_name_as::<i32>("age").unwrap_err();
+		assert_eq!(err.to_string(), "column \"age\": expected int, found string");
+
+		let r = row(vec![("age", ColumnType::Int, Column::None)]);
+		assert_eq!(r.get_as::<i32>(0).unwrap_err(), MappingError::UnexpectedNull(Some("age".to_string())));
+	}
+
+	#[test]
+	fn test_collections() {
+		let map = Column::Map(vec![(Column::String("a".to_string()), Column::Int(1))]);
+		let decoded: HashMap<String, i32> = FromCql::from_cql(&map).unwrap();
+		assert_eq!(decoded.get("a"), Some(&1));
+
+		let list = Column::List(vec![Column::Bigint(1), Column::Bigint(2)]);
+		assert_eq!(Vec::<i64>::from_cql(&list).unwrap(), vec![1, 2]);
+	}
+
+	#[test]
+	fn test_small_integers() {
+		assert_eq!(i8::from_cql(&Column::Tinyint(-3)).unwrap(), -3);
+		assert_eq!(i16::from_cql(&Column::Smallint(300)).unwrap(), 300);
+		assert_eq!(i16::from_cql(&Column::Tinyint(7)).unwrap(), 7);
+		assert_eq!(i32::from_cql(&Column::Smallint(-300)).unwrap(), -300);
+		assert_eq!(i64::from_cql(&Column::Tinyint(1)).unwrap(), 1);
+		assert!(i8::from_cql(&Column::Smallint(1)).is_err());
+	}
+
+	#[test]
+	fn test_into_values() {
+		let values = ("jsmith", 42, Some(1.5f32), None::<i64>).into_values();
+		assert_eq!(values, vec![
+			Column::String("jsmith".to_string()),
+			Column::Int(42),
+			Column::Float(1.5),
+			Column::Null
+		]);
+
+		let ids: &[i64] = &[1, 2];
+		assert_eq!(ids.into_values(), vec![Column::Bigint(1), Column::Bigint(2)]);
+
+		assert_eq!((1i8, 2i16).into_values(), vec![Column::Tinyint(1), Column::Smallint(2)]);
+	}
+}
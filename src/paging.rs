@@ -0,0 +1,192 @@
+use std::io::{
+    Result,
+    Error,
+    ErrorKind
+};
+
+use std::vec;
+
+use connection::Connection;
+
+use shared::{
+    Request,
+    Response,
+    ResultBody,
+    Row
+};
+
+use statement::Statement;
+
+use borrowed::RowsPage;
+
+/// Yields the rows of a paged statement one at a time, requesting the next
+/// page only once the current one is used up.
+///
+/// With `prefetch` enabled the request for the next page is written as soon
+/// as a page arrives, so the server works on it while the current page is
+/// being consumed. `AsyncConnection::rows` is the non-blocking counterpart.
+pub struct RowIterator<'a> {
+    conn: &'a mut Connection,
+    statement: Statement,
+    prefetch: bool,
+    rows: vec::IntoIter<Row>,
+    started: bool,
+    // stream id of the page requested and not read yet
+    pending: Option<i16>,
+    exhausted: bool,
+    warnings: Vec<String>
+}
+
+impl<'a> RowIterator<'a> {
+    /// Pages through `statement` starting at its paging state, `page_size` rows at a time.
+    pub fn new(conn: &'a mut Connection, statement: Statement) -> RowIterator<'a> {
+        RowIterator {
+            conn: conn,
+            statement: statement,
+            prefetch: false,
+            rows: Vec::new().into_iter(),
+            started: false,
+            pending: None,
+            exhausted: false,
+            warnings: vec!()
+        }
+    }
+
+    pub fn prefetch(mut self, prefetch: bool) -> RowIterator<'a> {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Paging state of the next page to be requested, `None` once the last page was read.
+    pub fn paging_state(&self) -> Option<&Vec<u8>> {
+        self.statement.paging_state.as_ref()
+    }
+
+    /// Warnings the server sent with the pages read so far.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn request_page(&mut self) -> Result<()> {
+        let stream = try!(self.conn.send(Request::Statement(self.statement.clone())));
+        self.started = true;
+        self.pending = Some(stream);
+        Ok(())
+    }
+
+    fn read_page(&mut self) -> Result<()> {
+        let stream = match self.pending.take() {
+            Some(stream) => stream,
+            None => return Err(Error::new(ErrorKind::InvalidInput, "No page was requested"))
+        };
+        let page = self.conn.receive_envelope(stream).and_then(|envelope| {
+            self.warnings.extend(envelope.warnings);
+            into_page(envelope.response)
+        });
+        match page {
+            Ok((rows, paging_state)) => {
+                self.rows = rows.into_iter();
+                self.exhausted = paging_state.is_none();
+                self.statement.paging_state = paging_state;
+                if self.prefetch && !self.exhausted {
+                    try!(self.request_page());
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.exhausted = true;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Splits a response into the rows of a page and the paging state of the next one.
+pub(crate) fn into_page(response: Response) -> Result<(Vec<Row>, Option<Vec<u8>>)> {
+    match response {
+        Response::Result(ResultBody::Rows(rows, paging_state)) => Ok((rows, paging_state)),
+        Response::Result(_) => Ok((vec!(), None)),
+        Response::Error(code, message) =>
+            Err(Error::new(ErrorKind::Other, format!("Error {:#x} while paging: {}", code, message))),
+        response =>
+            Err(Error::new(ErrorKind::InvalidData, format!("Unexpected response while paging: {:?}", response)))
+    }
+}
+
+impl<'a> Iterator for RowIterator<'a> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Result<Row>> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+            if self.pending.is_none() {
+                if self.started && self.exhausted {
+                    return None;
+                }
+                if let Err(e) = self.request_page() {
+                    self.exhausted = true;
+                    return Some(Err(e));
+                }
+            }
+            if let Err(e) = self.read_page() {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Yields the pages of a paged statement as `RowsPage`s, whose values are
+/// only decoded when they are read. Only one page is held at a time.
+pub struct PageIterator<'a> {
+    conn: &'a mut Connection,
+    statement: Statement,
+    exhausted: bool
+}
+
+impl<'a> PageIterator<'a> {
+    pub fn new(conn: &'a mut Connection, statement: Statement) -> PageIterator<'a> {
+        PageIterator {
+            conn: conn,
+            statement: statement,
+            exhausted: false
+        }
+    }
+
+    /// Paging state of the next page to be requested.
+    pub fn paging_state(&self) -> Option<&Vec<u8>> {
+        self.statement.paging_state.as_ref()
+    }
+}
+
+impl<'a> Iterator for PageIterator<'a> {
+    type Item = Result<RowsPage>;
+
+    fn next(&mut self) -> Option<Result<RowsPage>> {
+        if self.exhausted {
+            return None;
+        }
+        match self.conn.execute_borrowed(self.statement.clone()) {
+            Ok(page) => {
+                self.statement.paging_state = page.paging_state().cloned();
+                self.exhausted = self.statement.paging_state.is_none();
+                Some(Ok(page))
+            }
+            Err(e) => {
+                self.exhausted = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'a> Drop for RowIterator<'a> {
+    fn drop(&mut self) {
+        // a prefetched page nobody asked for is still on the wire, the connection
+        // drops it when it arrives
+        if let Some(stream) = self.pending.take() {
+            self.conn.abandon(stream);
+        }
+    }
+}
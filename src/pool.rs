@@ -0,0 +1,482 @@
+use std::io::{
+    Result,
+    Error,
+    ErrorKind
+};
+
+use std::mem;
+use std::ops::{
+    Deref,
+    DerefMut
+};
+
+use std::sync::{
+    Arc,
+    Weak,
+    Mutex,
+    MutexGuard,
+    Condvar
+};
+
+use std::thread;
+use std::time::{
+    Duration,
+    Instant
+};
+
+use connection::{
+    Connection,
+    connect_with_timeout
+};
+
+use shared::Compression;
+
+/// Sizes and timeouts of a `Pool`.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolConfig {
+    min_size: usize,
+    max_size: usize,
+    pub(crate) checkout_timeout: Duration,
+    reconnect_interval: Duration,
+    pub(crate) compression: Compression
+}
+
+impl PoolConfig {
+    pub fn new() -> PoolConfig {
+        PoolConfig {
+            min_size: 1,
+            max_size: 8,
+            checkout_timeout: Duration::from_secs(5),
+            reconnect_interval: Duration::from_secs(1),
+            compression: Compression::None
+        }
+    }
+
+    /// Connections opened up front and kept open.
+    pub fn min_size(mut self, min_size: usize) -> PoolConfig {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Connections opened at most, as checkouts find the others busy.
+    pub fn max_size(mut self, max_size: usize) -> PoolConfig {
+        self.max_size = max_size;
+        self
+    }
+
+    /// How long `get` waits for a connection before giving up, which also
+    /// bounds how long opening any one connection may take.
+    pub fn checkout_timeout(mut self, checkout_timeout: Duration) -> PoolConfig {
+        self.checkout_timeout = checkout_timeout;
+        self
+    }
+
+    /// How often broken connections are replaced in the background.
+    pub fn reconnect_interval(mut self, reconnect_interval: Duration) -> PoolConfig {
+        self.reconnect_interval = reconnect_interval;
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> PoolConfig {
+        self.compression = compression;
+        self
+    }
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig::new()
+    }
+}
+
+enum SlotState {
+    Idle(Box<Connection>),
+    // checked out
+    Busy,
+    // to be reopened in the background
+    Broken,
+    Reopening
+}
+
+impl SlotState {
+    fn usable(&self) -> bool {
+        match *self {
+            SlotState::Idle(_) | SlotState::Busy => true,
+            SlotState::Broken | SlotState::Reopening => false
+        }
+    }
+}
+
+struct Slots {
+    slots: Vec<SlotState>,
+    // connections being opened for a checkout
+    opening: usize
+}
+
+struct Inner {
+    addr: String,
+    config: PoolConfig,
+    slots: Mutex<Slots>,
+    // notified whenever a connection is returned, reopened or broken
+    changed: Condvar
+}
+
+impl Inner {
+    fn lock<'a>(&'a self) -> MutexGuard<'a, Slots> {
+        self.slots.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn connect(&self, timeout: Duration) -> Result<Connection> {
+        connect_with_timeout(self.addr.clone(), self.config.compression, timeout)
+    }
+}
+
+/// Connections to one host, each checked out by one user at a time, who has
+/// it to themselves until it is returned. Clones share the connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Arc<Inner>
+}
+
+impl Pool {
+    /// Opens the pool's `min_size` connections to `addr`.
+    pub fn new(addr: String, config: PoolConfig) -> Result<Pool> {
+        try!(check_sizes(config));
+
+        let mut slots = Vec::with_capacity(config.max_size);
+        for _ in 0..config.min_size {
+            let conn = try!(connect_with_timeout(addr.clone(), config.compression, config.checkout_timeout));
+            slots.push(SlotState::Idle(Box::new(conn)));
+        }
+        Ok(Pool::with_slots(addr, config, slots))
+    }
+
+    /// Like `new`, leaving the `min_size` connections, at least one, to be
+    /// opened in the background. Until one is, the pool has no usable
+    /// connection, as for a host that is down.
+    pub fn reconnecting(addr: String, config: PoolConfig) -> Result<Pool> {
+        try!(check_sizes(config));
+
+        let slots = (0..config.min_size.max(1))
+            .map(|_| SlotState::Broken)
+            .collect();
+        Ok(Pool::with_slots(addr, config, slots))
+    }
+
+    fn with_slots(addr: String, config: PoolConfig, slots: Vec<SlotState>) -> Pool {
+        let inner = Arc::new(Inner {
+            addr: addr,
+            config: config,
+            slots: Mutex::new(Slots { slots: slots, opening: 0 }),
+            changed: Condvar::new()
+        });
+
+        let weak = Arc::downgrade(&inner);
+        thread::spawn(move || reconnect(weak));
+
+        Pool { inner: inner }
+    }
+
+    /// Checks out an idle connection. While all are checked out, another is
+    /// opened if the pool isn't full, or else `get` waits for one to be returned.
+    pub fn get(&self) -> Result<PooledConnection> {
+        let inner = &self.inner;
+        let deadline = Instant::now() + inner.config.checkout_timeout;
+        let mut can_grow = true;
+        let mut slots = inner.lock();
+
+        loop {
+            let idle = slots.slots.iter().position(|slot| matches!(*slot, SlotState::Idle(_)));
+            if let Some(index) = idle {
+                if let SlotState::Idle(conn) = mem::replace(&mut slots.slots[index], SlotState::Busy) {
+                    return Ok(PooledConnection::new(inner.clone(), index, *conn));
+                }
+            }
+
+            let now = Instant::now();
+            if can_grow && now < deadline && slots.slots.len() + slots.opening < inner.config.max_size {
+                slots.opening += 1;
+                drop(slots);
+                let opened = inner.connect(deadline - now);
+                slots = inner.lock();
+                slots.opening -= 1;
+
+                match opened {
+                    Ok(conn) => {
+                        slots.slots.push(SlotState::Busy);
+                        let index = slots.slots.len() - 1;
+                        return Ok(PooledConnection::new(inner.clone(), index, conn));
+                    }
+                    Err(e) => {
+                        if !slots.slots.iter().any(SlotState::usable) {
+                            return Err(e);
+                        }
+                        // wait for one of the others instead
+                        can_grow = false;
+                        continue;
+                    }
+                }
+            }
+
+            // whichever connection comes back next will do
+            slots = try!(wait(inner, slots, deadline));
+        }
+    }
+
+    /// Connections open or being opened, broken ones included.
+    pub fn size(&self) -> usize {
+        let slots = self.inner.lock();
+        slots.slots.len() + slots.opening
+    }
+
+    /// Whether `get` has a connection to hand out or wait for: one is open,
+    /// or none broke and another may still be opened.
+    pub fn has_usable(&self) -> bool {
+        let slots = self.inner.lock();
+        let room = slots.slots.len() + slots.opening < self.inner.config.max_size;
+        slots.slots.iter().any(SlotState::usable) || (room && slots.slots.iter().all(SlotState::usable))
+    }
+}
+
+fn check_sizes(config: PoolConfig) -> Result<()> {
+    if config.max_size == 0 || config.min_size > config.max_size {
+        return Err(Error::new(ErrorKind::InvalidInput, "Pool sizes need 0 < max_size and min_size <= max_size"));
+    }
+    Ok(())
+}
+
+fn wait<'a>(inner: &'a Inner, slots: MutexGuard<'a, Slots>, deadline: Instant) -> Result<MutexGuard<'a, Slots>> {
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(Error::new(ErrorKind::TimedOut, "Timed out waiting for a pooled connection"));
+    }
+    let (slots, _) = inner.changed.wait_timeout(slots, deadline - now)
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    Ok(slots)
+}
+
+// Reopens broken connections until the pool is dropped.
+fn reconnect(pool: Weak<Inner>) {
+    loop {
+        let interval = match pool.upgrade() {
+            Some(inner) => inner.config.reconnect_interval,
+            None => return
+        };
+        thread::sleep(interval);
+
+        let inner = match pool.upgrade() {
+            Some(inner) => inner,
+            None => return
+        };
+
+        let broken: Vec<usize> = {
+            let mut slots = inner.lock();
+            let mut broken = vec!();
+            for (index, slot) in slots.slots.iter_mut().enumerate() {
+                if let SlotState::Broken = *slot {
+                    *slot = SlotState::Reopening;
+                    broken.push(index);
+                }
+            }
+            broken
+        };
+
+        for index in broken {
+            let state = match inner.connect(inner.config.checkout_timeout) {
+                Ok(conn) => SlotState::Idle(Box::new(conn)),
+                Err(_) => SlotState::Broken
+            };
+            inner.lock().slots[index] = state;
+            inner.changed.notify_all();
+        }
+    }
+}
+
+/// A checked out connection, returned to the pool when dropped. A connection
+/// that broke while checked out, or still has responses to read, is replaced
+/// in the background.
+pub struct PooledConnection {
+    pool: Arc<Inner>,
+    index: usize,
+    conn: Option<Connection>
+}
+
+impl PooledConnection {
+    fn new(pool: Arc<Inner>, index: usize, conn: Connection) -> PooledConnection {
+        PooledConnection {
+            pool: pool,
+            index: index,
+            conn: Some(conn)
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        let conn = self.conn.take().unwrap();
+        // unread responses would be taken for those of the next user
+        let state = if conn.is_broken() || conn.in_flight() > 0 {
+            SlotState::Broken
+        } else {
+            SlotState::Idle(Box::new(conn))
+        };
+
+        self.pool.lock().slots[self.index] = state;
+        self.pool.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{
+        Read,
+        Write
+    };
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{
+        Duration,
+        Instant
+    };
+
+    use super::*;
+
+    // Answers every frame with READY, which is all opening a connection needs.
+    pub(crate) fn ready_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut header = [0u8; 9];
+                    while stream.read_exact(&mut header).is_ok() {
+                        let len = ((header[5] as usize) << 24) | ((header[6] as usize) << 16)
+                            | ((header[7] as usize) << 8) | header[8] as usize;
+                        let mut body = vec![0; len];
+                        stream.read_exact(&mut body).unwrap();
+                        let ready = [header[0] | 0x80, 0, header[2], header[3], 0x02, 0, 0, 0, 0];
+                        stream.write_all(&ready).unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_checkout() {
+        let config = PoolConfig::new()
+            .min_size(1)
+            .max_size(2)
+            .checkout_timeout(Duration::from_millis(50));
+        let pool = Pool::new(ready_server(), config).unwrap();
+        assert_eq!(pool.size(), 1);
+
+        let first = pool.get().unwrap();
+        // the first is busy, so a second is opened
+        let second = pool.get().unwrap();
+        assert_eq!(pool.size(), 2);
+
+        let err = pool.get().err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        drop(first);
+        let third = pool.get().unwrap();
+        assert_eq!(pool.size(), 2);
+        drop(second);
+        drop(third);
+    }
+
+    #[test]
+    fn test_waiting_checkout() {
+        let config = PoolConfig::new()
+            .min_size(1)
+            .max_size(1)
+            .checkout_timeout(Duration::from_secs(5));
+        let pool = Pool::new(ready_server(), config).unwrap();
+
+        let held = pool.get().unwrap();
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get().map(|_| ()))
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(held);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_waiting_for_any() {
+        let config = PoolConfig::new()
+            .min_size(2)
+            .max_size(2)
+            .checkout_timeout(Duration::from_secs(5));
+        let pool = Pool::new(ready_server(), config).unwrap();
+
+        let first = pool.get().unwrap();
+        let second = pool.get().unwrap();
+        let waiter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.get().map(|_| ()))
+        };
+        thread::sleep(Duration::from_millis(20));
+        // the waiter takes the second back though the first was held as long
+        drop(second);
+        waiter.join().unwrap().unwrap();
+        drop(first);
+    }
+
+    #[test]
+    fn test_silent_server() {
+        // connections are accepted but STARTUP is never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let config = PoolConfig::new().checkout_timeout(Duration::from_millis(50));
+        let started = Instant::now();
+        let err = Pool::new(addr, config).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_reconnecting() {
+        let config = PoolConfig::new()
+            .min_size(1)
+            .max_size(1)
+            .reconnect_interval(Duration::from_millis(10));
+        let pool = Pool::reconnecting(ready_server(), config).unwrap();
+        assert!(!pool.has_usable());
+
+        // the reconnect thread opens it shortly
+        let started = Instant::now();
+        while !pool.has_usable() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(5));
+        }
+        pool.get().unwrap();
+    }
+
+    #[test]
+    fn test_sizes() {
+        let config = PoolConfig::new().min_size(2).max_size(1);
+        assert!(Pool::new("127.0.0.1:1".to_string(), config).is_err());
+        assert!(Pool::reconnecting("127.0.0.1:1".to_string(), config).is_err());
+    }
+}
@@ -1,57 +1,232 @@
 use std::io::{
 	Read,
-	BufReader,
-	Result
+	Cursor,
+	Result,
+	Error,
+	ErrorKind
 };
 
+use std::sync::Arc;
 use std::collections::HashMap;
+use std::mem;
 
 use byteorder::{
 	BigEndian,
 	ReadBytesExt
 };
 
+use uuid::Uuid;
+
 use shared::{
+	Envelope,
 	Response,
 	ResultBody,
 	Row,
+	ColumnSpec,
+    FrameFlag,
     Opcode,
     to_opcode,
     ResultKind,
     to_result_kind,
-    RowsFlag
+    RowsFlag,
+    ProtocolVersion
 };
 
-use reading::spec::read_column_specs;
+use borrowed::RowsPage;
+
+use reading::spec::read_metadata_specs;
 use reading::value::read_column_value;
 
 
 pub trait ReadMessage {
     fn read_message(&mut self) -> Result<Response>;
+    /// Like `read_message`, with the result metadata to use for rows sent without it.
+    fn read_message_with_metadata(&mut self, metadata: Option<Arc<Vec<ColumnSpec>>>) -> Result<Response>;
+    /// Like `read_message_with_metadata`, keeping the tracing id that came with the response.
+    fn read_envelope(&mut self, metadata: Option<Arc<Vec<ColumnSpec>>>) -> Result<Envelope>;
 }
 
 impl<R: Read> ReadMessage for R {
     fn read_message(&mut self) -> Result<Response> {
-        let mut buf = BufReader::new(self);
-        let version = try!(buf.read_u8());
-        let flags = try!(buf.read_u8());
-        let stream = try!(buf.read_u16::<BigEndian>());
-        let opcode = to_opcode(try!(buf.read_u8()));
-
-        try!(buf.read_u32::<BigEndian>()); // length
-
-
-        let ret = match opcode {
-                Opcode::Error => try!(read_error_response(&mut buf)),
-                Opcode::Ready => Response::Ready,
-                Opcode::Authenticate => Response::Authenticate("test".to_string()),
-                Opcode::Supported => Response::Supported,
-                Opcode::Result => try!(read_result(&mut buf)),
-                _ => Response::Empty
+        self.read_message_with_metadata(None)
+    }
+
+    fn read_message_with_metadata(&mut self, metadata: Option<Arc<Vec<ColumnSpec>>>) -> Result<Response> {
+        self.read_envelope(metadata).map(|envelope| envelope.response)
+    }
+
+    fn read_envelope(&mut self, metadata: Option<Arc<Vec<ColumnSpec>>>) -> Result<Envelope> {
+        read_frame(self).and_then(|frame| decode_envelope(frame, metadata))
+    }
+}
+
+/// A response frame whose body was read but not decoded yet.
+pub struct Frame {
+    pub version: ProtocolVersion,
+    pub flags: u8,
+    /// Stream id of the request this answers, -1 for server events.
+    pub stream: i16,
+    pub opcode: Opcode,
+    pub body: Cursor<Vec<u8>>
+}
+
+/// What the frame flags put in front of the body.
+pub struct FramePrefix {
+    pub tracing_id: Option<Uuid>,
+    pub warnings: Vec<String>,
+    pub custom_payload: HashMap<String, Vec<u8>>
+}
+
+pub fn read_frame(buf: &mut Read) -> Result<Frame> {
+    // decode for the version the server answered with
+    let version = ProtocolVersion::from_u8(try!(buf.read_u8())).unwrap_or(ProtocolVersion::V4);
+    let flags = try!(buf.read_u8());
+    let stream = try!(buf.read_i16::<BigEndian>());
+    let opcode = to_opcode(try!(buf.read_u8()));
+
+    let length = try!(buf.read_u32::<BigEndian>());
+
+    // take exactly this frame's body off the stream, so whatever the decoders
+    // skip over can't end up in front of the next response
+    let mut body = vec![0; length as usize];
+    try!(buf.read_exact(&mut body));
+
+    Ok(Frame {
+        version: version,
+        flags: flags,
+        stream: stream,
+        opcode: opcode,
+        body: Cursor::new(body)
+    })
+}
+
+pub fn read_prefix(frame: &mut Frame) -> Result<FramePrefix> {
+    let flags = frame.flags;
+    let body = &mut frame.body;
+
+    let mut tracing_id = None;
+    if flags & FrameFlag::Tracing as u8 != 0 {
+        let bytes = try!(read_exactly(body, 16));
+        tracing_id = Uuid::from_bytes(&bytes).ok();
+    }
+    let mut warnings = vec!();
+    if flags & FrameFlag::Warning as u8 != 0 {
+        warnings = try!(read_string_list(body));
+    }
+    let mut custom_payload = HashMap::new();
+    if flags & FrameFlag::CustomPayload as u8 != 0 {
+        custom_payload = try!(read_bytes_map(body));
+    }
+
+    Ok(FramePrefix {
+        tracing_id: tracing_id,
+        warnings: warnings,
+        custom_payload: custom_payload
+    })
+}
+
+pub fn decode_envelope(mut frame: Frame, metadata: Option<Arc<Vec<ColumnSpec>>>) -> Result<Envelope> {
+    let prefix = try!(read_prefix(&mut frame));
+    let version = frame.version;
+    let body = &mut frame.body;
+
+    let mut metadata_changed = None;
+    let ret = match frame.opcode {
+            Opcode::Error => try!(read_error_response(body)),
+            Opcode::Ready => Response::Ready,
+            Opcode::Authenticate => Response::Authenticate("test".to_string()),
+            Opcode::Supported => Response::Supported,
+            Opcode::Result => try!(read_result(body, metadata, version, &mut metadata_changed)),
+            _ => Response::Empty
+        };
+
+    Ok(Envelope {
+        tracing_id: prefix.tracing_id,
+        warnings: prefix.warnings,
+        custom_payload: prefix.custom_payload,
+        metadata_changed: metadata_changed,
+        response: ret
+    })
+}
+
+/// Decodes a rows result without copying its values: the page keeps the frame
+/// body and only records where each value starts.
+pub fn decode_rows_page(mut frame: Frame, metadata: Option<Arc<Vec<ColumnSpec>>>) -> Result<RowsPage> {
+    let prefix = try!(read_prefix(&mut frame));
+    let body = &mut frame.body;
+
+    match frame.opcode {
+        Opcode::Result => {}
+        Opcode::Error => {
+            return match try!(read_error_response(body)) {
+                Response::Error(code, message) =>
+                    Err(Error::new(ErrorKind::Other, format!("Error {:#x}: {}", code, message))),
+                _ => unreachable!()
             };
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Expected a result frame"))
+    }
 
-        Ok(ret)
+    let mut page = RowsPage::empty();
+    page.tracing_id = prefix.tracing_id;
+    page.warnings = prefix.warnings;
+    page.custom_payload = prefix.custom_payload;
+    match to_result_kind(try!(body.read_u32::<BigEndian>())) {
+        ResultKind::Rows => {}
+        _ => return Ok(page)
     }
+
+    let flags = try!(body.read_i32::<BigEndian>());
+    let columns_count = try!(body.read_i32::<BigEndian>());
+    if flags & RowsFlag::HasMorePages as i32 != 0 {
+        let len = try!(body.read_i32::<BigEndian>());
+        page.paging_state = Some(try!(take(body, len as usize)).to_vec());
+    }
+    if flags & RowsFlag::MetadataChanged as i32 != 0 {
+        let len = try!(body.read_u16::<BigEndian>());
+        page.new_metadata_id = Some(try!(take(body, len as usize)).to_vec());
+    }
+    page.specs = if flags & RowsFlag::NoMetadata as i32 != 0 {
+        match metadata {
+            Some(specs) => specs,
+            None => return Err(Error::new(ErrorKind::InvalidData, "Rows sent without metadata for an unknown statement"))
+        }
+    } else {
+        Arc::new(read_metadata_specs(body, flags, columns_count))
+    };
+
+    let row_count = try!(body.read_i32::<BigEndian>());
+    // every value takes at least its length, so the body bounds how many there can be
+    let remaining = body.get_ref().len() - body.position() as usize;
+    let mut cells = Vec::with_capacity((row_count.max(0) as usize).saturating_mul(page.specs.len()).min(remaining / 4));
+    for _ in 0..row_count {
+        for _ in 0..page.specs.len() {
+            let len = try!(body.read_i32::<BigEndian>());
+            let start = body.position() as usize;
+            if len > 0 {
+                try!(take(body, len as usize));
+            }
+            cells.push((start, len));
+        }
+    }
+    page.cells = cells;
+    page.row_count = row_count.max(0) as usize;
+
+    let position = body.position() as usize;
+    let mut bytes = mem::take(body.get_mut());
+    bytes.truncate(position);
+    page.body = bytes;
+    Ok(page)
+}
+
+/// Skips `len` bytes of the body and returns them, or fails if the body is shorter.
+fn take(body: &mut Cursor<Vec<u8>>, len: usize) -> Result<&[u8]> {
+    let start = body.position() as usize;
+    if start + len > body.get_ref().len() {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "Value runs past the end of the frame"));
+    }
+    body.set_position((start + len) as u64);
+    Ok(&body.get_ref()[start..start + len])
 }
 
 pub fn read_fixed(rdr: &mut Read, len: usize) -> Vec<u8> {		
@@ -71,6 +246,37 @@ pub fn read_fixed(rdr: &mut Read, len: usize) -> Vec<u8> {
     buf
 }
 
+/// Reads `len` bytes, failing with `UnexpectedEof` if the frame ends first.
+fn read_exactly(buf: &mut Read, len: usize) -> Result<Vec<u8>> {
+	let mut bytes = vec![0; len];
+	try!(buf.read_exact(&mut bytes));
+	Ok(bytes)
+}
+
+fn read_string_list(buf: &mut Read) -> Result<Vec<String>> {
+	let count = try!(buf.read_u16::<BigEndian>());
+	let mut list = Vec::with_capacity(count as usize);
+	for _ in 0..count {
+		let len = try!(buf.read_u16::<BigEndian>());
+		let string_bytes = try!(read_exactly(buf, len as usize));
+		list.push(String::from_utf8_lossy(&string_bytes).into_owned());
+	}
+	Ok(list)
+}
+
+fn read_bytes_map(buf: &mut Read) -> Result<HashMap<String, Vec<u8>>> {
+	let count = try!(buf.read_u16::<BigEndian>());
+	let mut map = HashMap::with_capacity(count as usize);
+	for _ in 0..count {
+		let len = try!(buf.read_u16::<BigEndian>());
+		let key = String::from_utf8_lossy(&try!(read_exactly(buf, len as usize))).into_owned();
+		let len = try!(buf.read_i32::<BigEndian>());
+		let value = if len < 0 { vec!() } else { try!(read_exactly(buf, len as usize)) };
+		map.insert(key, value);
+	}
+	Ok(map)
+}
+
 fn read_error_response(buf: &mut Read) -> Result<Response> {	
 	let code = try!(buf.read_u32::<BigEndian>());
 	let len = try!(buf.read_u16::<BigEndian>());
@@ -82,7 +288,9 @@ fn read_error_response(buf: &mut Read) -> Result<Response> {
 		})
 }
 
-fn read_result(buf: &mut Read) -> Result<Response> {
+// A new result metadata id, with the metadata it stands for, is put in `metadata_changed`.
+fn read_result(buf: &mut Read, metadata: Option<Arc<Vec<ColumnSpec>>>, version: ProtocolVersion,
+	metadata_changed: &mut Option<(Vec<u8>, Arc<Vec<ColumnSpec>>)>) -> Result<Response> {
 	let result_kind = to_result_kind(try!(buf.read_u32::<BigEndian>()));
 
 	let body = match result_kind {
@@ -102,31 +310,35 @@ fn read_result(buf: &mut Read) -> Result<Response> {
                     paging_state = Some(read_fixed(buf, len as usize));
                 }
 
-                //assume global column spec
-                let len = try!(buf.read_u16::<BigEndian>());
-                let bytes = read_fixed(buf, len as usize);
-                let keyspace = String::from_utf8(bytes).unwrap();
-
-                let len = try!(buf.read_u16::<BigEndian>());
-                let string_bytes = read_fixed(buf, len as usize);
-                let table = String::from_utf8(string_bytes).unwrap();
-
-                //println!("The flags are {}, and column count is {}", flags, columns_count);
-                //println!("The keyspace is {}, and table is {}", keyspace, table);
+                let mut new_metadata_id = None;
+                if flags & RowsFlag::MetadataChanged as i32 != 0 {
+                    let len = try!(buf.read_u16::<BigEndian>());
+                    new_metadata_id = Some(try!(read_exactly(buf, len as usize)));
+                }
 
-                let column_specs = read_column_specs(buf, columns_count);
+                let column_specs = if flags & RowsFlag::NoMetadata as i32 != 0 {
+                    match metadata {
+                        Some(specs) => specs,
+                        None => return Err(Error::new(ErrorKind::InvalidData, "Rows sent without metadata for an unknown statement"))
+                    }
+                } else {
+                    Arc::new(read_metadata_specs(buf, flags, columns_count))
+                };
+                if let Some(id) = new_metadata_id {
+                    *metadata_changed = Some((id, column_specs.clone()));
+                }
                 let row_count = try!(buf.read_i32::<BigEndian>());
                 let mut rows = vec!();
                 //println!("Row count: {}", row_count);
 
                 for _ in 0..row_count {
-                    let mut columns = HashMap::new();
+                    let mut columns = Vec::with_capacity(column_specs.len());
                     for col_spec in column_specs.iter() {
                         //println!("started column {:?}", col_spec);
-                        columns.insert(col_spec.name.clone(), read_column_value(buf, col_spec.data_type, col_spec.collection_spec.clone()));
+                        columns.push(read_column_value(buf, col_spec.data_type, &col_spec.collection_spec));
                         //println!("finished column");
                     }
-                    rows.push(Row { columns: columns});
+                    rows.push(Row { specs: column_specs.clone(), columns: columns });
                 }
                 ResultBody::Rows(rows, paging_state)
 			}
@@ -139,32 +351,33 @@ fn read_result(buf: &mut Read) -> Result<Response> {
             ResultKind::Prepared => {
 				let len = try!(buf.read_u16::<BigEndian>());
 				let id = read_fixed(buf, len as usize);
-				let flags = try!(buf.read_i32::<BigEndian>());
-				//println!("flags is {}", flags);
-				let columns_count = try!(buf.read_i32::<BigEndian>());
-				//println!("columns_count is {}", columns_count);
-				let pk_count = try!(buf.read_i32::<BigEndian>());
-				//println!("pk_count is {}", pk_count);
 
-				for _ in 0..pk_count {
-					let pk_index = try!(buf.read_u16::<BigEndian>());
+				let mut result_metadata_id = None;
+				if version >= ProtocolVersion::V5 {
+					let len = try!(buf.read_u16::<BigEndian>());
+					result_metadata_id = Some(try!(read_exactly(buf, len as usize)));
 				}
 
-				//assume global column spec
-
-				let len = try!(buf.read_u16::<BigEndian>());
-				let bytes = read_fixed(buf, len as usize);
-				let keyspace = String::from_utf8(bytes).unwrap();
-
-				let len = try!(buf.read_u16::<BigEndian>());
-				let string_bytes = read_fixed(buf, len as usize);
-				let table = String::from_utf8(string_bytes).unwrap();
-
-				//println!("The flags are {}, and column count is {}", flags, columns_count);
-				//println!("The keyspace is {}, and table is {}", keyspace, table);
+				// bind variables
+				let flags = try!(buf.read_i32::<BigEndian>());
+				let columns_count = try!(buf.read_i32::<BigEndian>());
+				if version >= ProtocolVersion::V4 {
+					let pk_count = try!(buf.read_i32::<BigEndian>());
+					for _ in 0..pk_count {
+						try!(buf.read_u16::<BigEndian>());
+					}
+				}
+				read_metadata_specs(buf, flags, columns_count);
 
-				let column_specs = read_column_specs(buf, columns_count);
-				ResultBody::Prepared(id)
+				// result columns, kept for executing with skip_metadata
+				let flags = try!(buf.read_i32::<BigEndian>());
+				let columns_count = try!(buf.read_i32::<BigEndian>());
+				let result_specs = if flags & RowsFlag::NoMetadata as i32 != 0 {
+					vec!()
+				} else {
+					read_metadata_specs(buf, flags, columns_count)
+				};
+				ResultBody::Prepared(id, Arc::new(result_specs), result_metadata_id)
 			}
             ResultKind::Schema_change => {
                 // dedup this - map over range?
@@ -185,4 +398,105 @@ fn read_result(buf: &mut Read) -> Result<Response> {
 		};
 
 	Ok(Response::Result(body))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use shared::{
+        Response,
+        ResultBody
+    };
+
+    use super::ReadMessage;
+
+    #[test]
+    fn test_tracing_id() {
+        let mut frame = vec![0x84, 0x02, 0, 1, 0x08, 0, 0, 0, 20];
+        frame.extend_from_slice(&[7; 16]);
+        frame.extend_from_slice(&[0, 0, 0, 1]); // void result
+
+        let envelope = (&frame[..]).read_envelope(None).unwrap();
+        assert_eq!(envelope.tracing_id.unwrap().as_bytes(), &[7; 16]);
+        match envelope.response {
+            Response::Result(_) => {}
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+
+    #[test]
+    fn test_custom_payload() {
+        let mut frame = vec![0x84, 0x0C, 0, 1, 0x08, 0, 0, 0, 20];
+        frame.extend_from_slice(&[0, 1, 0, 1, b'w']);     // warnings
+        frame.extend_from_slice(&[0, 1, 0, 1, b'k', 0, 0, 0, 2, 1, 2]);
+        frame.extend_from_slice(&[0, 0, 0, 1]); // void result
+
+        let envelope = (&frame[..]).read_envelope(None).unwrap();
+        assert_eq!(envelope.warnings, vec!["w".to_string()]);
+        assert_eq!(envelope.custom_payload.get("k"), Some(&vec![1, 2]));
+    }
+
+    #[test]
+    fn test_warnings() {
+        let mut frame = vec![0x84, 0x08, 0, 1, 0x08, 0, 0, 0, 13];
+        frame.extend_from_slice(&[0, 1, 0, 5]);
+        frame.extend_from_slice(b"large");
+        frame.extend_from_slice(&[0, 0, 0, 1]); // void result
+
+        let envelope = (&frame[..]).read_envelope(None).unwrap();
+        assert_eq!(envelope.warnings, vec!["large".to_string()]);
+        assert!(envelope.custom_payload.is_empty());
+        assert!(envelope.tracing_id.is_none());
+        match envelope.response {
+            Response::Result(_) => {}
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+
+    #[test]
+    fn test_truncated_prefix() {
+        // the tracing id is cut short
+        let frame = vec![0x84, 0x02, 0, 1, 0x08, 0, 0, 0, 4, 1, 2, 3, 4];
+        assert_eq!((&frame[..]).read_envelope(None).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // the warning claims 5 bytes, the frame ends after 3
+        let mut frame = vec![0x84, 0x08, 0, 1, 0x08, 0, 0, 0, 7];
+        frame.extend_from_slice(&[0, 1, 0, 5]);
+        frame.extend_from_slice(b"lar");
+        assert_eq!((&frame[..]).read_envelope(None).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+
+        // a custom payload value past the end of the frame
+        let mut frame = vec![0x84, 0x04, 0, 1, 0x08, 0, 0, 0, 9];
+        frame.extend_from_slice(&[0, 1, 0, 1, b'k', 0, 0, 0, 9]);
+        assert_eq!((&frame[..]).read_envelope(None).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_prepared_versions() {
+        // v3 has no partition key indexes
+        let mut frame = vec![0x83, 0x00, 0, 1, 0x08, 0, 0, 0, 23];
+        frame.extend_from_slice(&[0, 0, 0, 4, 0, 1, 9]);
+        frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0]);   // bind metadata
+        frame.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0]);   // result metadata
+
+        match (&frame[..]).read_message().unwrap() {
+            Response::Result(ResultBody::Prepared(id, specs, None)) => {
+                assert_eq!(id, vec![9]);
+                assert!(specs.is_empty());
+            }
+            response => panic!("unexpected response {:?}", response)
+        }
+
+        // v5 adds the result metadata id
+        let mut frame = vec![0x85, 0x00, 0, 1, 0x08, 0, 0, 0, 30];
+        frame.extend_from_slice(&[0, 0, 0, 4, 0, 1, 9, 0, 1, 8]);
+        frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        frame.extend_from_slice(&[0, 0, 0, 4, 0, 0, 0, 0]);
+
+        match (&frame[..]).read_message().unwrap() {
+            Response::Result(ResultBody::Prepared(_, _, Some(result_metadata_id))) =>
+                assert_eq!(result_metadata_id, vec![8]),
+            response => panic!("unexpected response {:?}", response)
+        }
+    }
+}
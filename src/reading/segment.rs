@@ -0,0 +1,161 @@
+use std::io::{
+	Read,
+	Result,
+	Error,
+	ErrorKind
+};
+
+use std::cmp::min;
+
+use byteorder::{
+	LittleEndian,
+	ByteOrder
+};
+
+#[cfg(feature = "lz4")]
+use lz4_flex::block::decompress;
+
+use checksum::{
+	crc24,
+	crc32
+};
+
+use shared::Compression;
+
+/// Payload of the last segment read that hasn't been consumed yet. It lives
+/// on the connection, since a segment may hold several frames.
+pub struct SegmentBuffer {
+	compression: Compression,
+	payload: Vec<u8>,
+	position: usize
+}
+
+impl SegmentBuffer {
+	pub fn new(compression: Compression) -> SegmentBuffer {
+		SegmentBuffer {
+			compression: compression,
+			payload: vec!(),
+			position: 0
+		}
+	}
+
+	/// Frames are read from the returned reader as if there were no segments.
+	pub fn reader<'a, R: Read>(&'a mut self, inner: &'a mut R) -> SegmentReader<'a, R> {
+		SegmentReader {
+			buffer: self,
+			inner: inner
+		}
+	}
+}
+
+pub struct SegmentReader<'a, R: 'a> {
+	buffer: &'a mut SegmentBuffer,
+	inner: &'a mut R
+}
+
+impl<'a, R: Read> Read for SegmentReader<'a, R> {
+	fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+		while self.buffer.position >= self.buffer.payload.len() {
+			self.buffer.payload = try!(read_segment(self.inner, self.buffer.compression));
+			self.buffer.position = 0;
+		}
+		let n = min(buf.len(), self.buffer.payload.len() - self.buffer.position);
+		buf[..n].copy_from_slice(&self.buffer.payload[self.buffer.position..self.buffer.position + n]);
+		self.buffer.position += n;
+		Ok(n)
+	}
+}
+
+fn corrupt(what: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, format!("Corrupt segment: {} checksum mismatch", what))
+}
+
+/// Reads one segment, checks both checksums and returns the uncompressed payload.
+pub fn read_segment(buf: &mut dyn Read, compression: Compression) -> Result<Vec<u8>> {
+	let header_len = match compression {
+		Compression::None => 3,
+		#[cfg(feature = "lz4")]
+		Compression::Lz4 => 5
+	};
+
+	let mut header = [0u8; 8];
+	try!(buf.read_exact(&mut header[..header_len + 3]));
+	if LittleEndian::read_uint(&header[header_len..], 3) as u32 != crc24(&header[..header_len]) {
+		return Err(corrupt("header"));
+	}
+	let fields = LittleEndian::read_uint(&header, header_len);
+	let payload_len = (fields & 0x1FFFF) as usize;
+
+	let mut payload = vec![0; payload_len];
+	try!(buf.read_exact(&mut payload));
+	let mut crc = [0u8; 4];
+	try!(buf.read_exact(&mut crc));
+	if LittleEndian::read_u32(&crc) != crc32(&payload) {
+		return Err(corrupt("payload"));
+	}
+
+	match compression {
+		Compression::None => Ok(payload),
+		#[cfg(feature = "lz4")]
+		Compression::Lz4 => {
+			// an uncompressed length of 0 means the payload was stored as is
+			let uncompressed_len = ((fields >> 17) & 0x1FFFF) as usize;
+			if uncompressed_len == 0 {
+				return Ok(payload);
+			}
+			decompress(&payload, uncompressed_len)
+				.map_err(|e| Error::new(ErrorKind::InvalidData, format!("Corrupt segment: {}", e)))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use shared::Compression;
+
+	use writing::write_segments;
+
+	use super::*;
+
+	fn round_trip(frame: &[u8], compression: Compression) {
+		let mut wire = Vec::new();
+		write_segments(&mut wire, frame, compression).unwrap();
+
+		let mut buffer = SegmentBuffer::new(compression);
+		let mut input = &wire[..];
+		let mut read = vec![0; frame.len()];
+		buffer.reader(&mut input).read_exact(&mut read).unwrap();
+		assert_eq!(&read[..], frame);
+		assert!(input.is_empty());
+	}
+
+	#[test]
+	fn test_round_trip() {
+		round_trip(b"a small frame", Compression::None);
+		// spread over two segments
+		round_trip(&vec![7; 200000], Compression::None);
+	}
+
+	#[cfg(feature = "lz4")]
+	#[test]
+	fn test_round_trip_lz4() {
+		round_trip(b"too short to compress", Compression::Lz4);
+		round_trip(&vec![7; 200000], Compression::Lz4);
+	}
+
+	#[test]
+	fn test_corruption() {
+		let mut wire = Vec::new();
+		write_segments(&mut wire, b"payload", Compression::None).unwrap();
+
+		let mut header = wire.clone();
+		header[0] ^= 1;
+		assert!(read_segment(&mut &header[..], Compression::None).is_err());
+
+		let mut payload = wire.clone();
+		payload[7] ^= 1;
+		assert!(read_segment(&mut &payload[..], Compression::None).is_err());
+
+		assert_eq!(read_segment(&mut &wire[..], Compression::None).unwrap(), b"payload".to_vec());
+	}
+}
@@ -8,15 +8,30 @@ use byteorder::{
 use shared::{
 	ColumnSpec,
 	CollectionSpec,
+	ElementSpec,
 	ColumnType,
+	RowsFlag,
 	to_column_type
 };
 
 use reading::reader::read_fixed;
 
-pub fn read_column_specs(buf: &mut Read, column_count: i32) -> Vec<ColumnSpec> {
+/// Reads the column specs of a metadata block whose flags and column count were
+/// already read, with or without the global table spec.
+pub fn read_metadata_specs(buf: &mut Read, flags: i32, column_count: i32) -> Vec<ColumnSpec> {
+	let global = flags & RowsFlag::GlobalTablesSpec as i32 != 0;
+	if global {
+		skip_table_spec(buf);
+	}
+	read_column_specs(buf, column_count, global)
+}
+
+pub fn read_column_specs(buf: &mut Read, column_count: i32, global: bool) -> Vec<ColumnSpec> {
 	let mut column_specs = vec!();
 	for _ in 0..column_count {
+		if !global {
+			skip_table_spec(buf);
+		}
 		let len = buf.read_u16::<BigEndian>().unwrap();
 		let bytes = read_fixed(buf, len as usize);
 		match3(buf, String::from_utf8(bytes).unwrap(), &mut column_specs);
@@ -24,40 +39,117 @@ pub fn read_column_specs(buf: &mut Read, column_count: i32) -> Vec<ColumnSpec> {
 	column_specs
 }
 
+fn skip_table_spec(buf: &mut Read) {
+	let len = buf.read_u16::<BigEndian>().unwrap();
+	read_fixed(buf, len as usize); // keyspace
+	let len = buf.read_u16::<BigEndian>().unwrap();
+	read_fixed(buf, len as usize); // table
+}
+
 fn match3(buf: &mut Read, column_name: String, column_specs: &mut Vec<ColumnSpec>) {
-	let column_type_u16 = buf.read_u16::<BigEndian>().unwrap();
-	let column_type = to_column_type(column_type_u16);
-	let mut spec = ColumnSpec {
+	let element = read_element_spec(buf);
+	let spec = ColumnSpec {
 		name: column_name,
-		data_type: column_type,
-		collection_spec: CollectionSpec::None
-	};
-	match column_type {
-		ColumnType::Set | ColumnType::List | ColumnType::Map =>
-			match2(buf, column_type, &mut spec),
-		_ => {}
+		data_type: element.data_type,
+		collection_spec: element.collection_spec
 	};
 	//println!("Dat spec: {:?}", spec);
 	column_specs.push(spec);
 }
 
-fn match2(buf: &mut Read, column_type: ColumnType, spec: &mut ColumnSpec) {
-	let coll_col_type_u16 = buf.read_u16::<BigEndian>().unwrap();
-	let coll_col_type = to_column_type(coll_col_type_u16);
-	match1(buf, column_type, coll_col_type, spec);
-}
-
-fn match1(buf: &mut Read, column_type: ColumnType, collection_column_type: ColumnType, spec: &mut ColumnSpec) {
-	match column_type {
+/// Reads an [option] type, along with the types nested in it.
+fn read_element_spec(buf: &mut Read) -> ElementSpec {
+	let data_type = to_column_type(buf.read_u16::<BigEndian>().unwrap());
+	let collection_spec = match data_type {
 		ColumnType::Set =>
-			spec.collection_spec = CollectionSpec::Set(collection_column_type),
+			CollectionSpec::Set(Box::new(read_element_spec(buf))),
 		ColumnType::List =>
-			spec.collection_spec = CollectionSpec::List(collection_column_type),
+			CollectionSpec::List(Box::new(read_element_spec(buf))),
 		ColumnType::Map => {
-			let map_value_type_u16 = buf.read_u16::<BigEndian>().unwrap();
-			let map_value_type = to_column_type(map_value_type_u16);
-			spec.collection_spec = CollectionSpec::Map(collection_column_type, map_value_type);
+			let key = read_element_spec(buf);
+			CollectionSpec::Map(Box::new(key), Box::new(read_element_spec(buf)))
+		}
+		ColumnType::Tuple =>
+			read_tuple_spec(buf),
+		ColumnType::UDT =>
+			read_udt_spec(buf),
+		_ => CollectionSpec::None
+	};
+	ElementSpec {
+		data_type: data_type,
+		collection_spec: collection_spec
+	}
+}
+
+fn read_tuple_spec(buf: &mut Read) -> CollectionSpec {
+	let count = buf.read_u16::<BigEndian>().unwrap();
+	let mut types = vec!();
+	for _ in 0..count {
+		types.push(read_element_spec(buf));
+	}
+	CollectionSpec::Tuple(types)
+}
+
+fn read_udt_spec(buf: &mut Read) -> CollectionSpec {
+	let len = buf.read_u16::<BigEndian>().unwrap();
+	read_fixed(buf, len as usize); // keyspace
+	let len = buf.read_u16::<BigEndian>().unwrap();
+	read_fixed(buf, len as usize); // type name
+	let count = buf.read_u16::<BigEndian>().unwrap();
+	let mut fields = vec!();
+	for _ in 0..count {
+		let len = buf.read_u16::<BigEndian>().unwrap();
+		let name = String::from_utf8(read_fixed(buf, len as usize)).unwrap();
+		fields.push((name, read_element_spec(buf)));
+	}
+	CollectionSpec::Udt(fields)
+}
+
+#[cfg(test)]
+mod tests {
+	use shared::{
+		Column,
+		ColumnType,
+		CollectionSpec
+	};
+
+	use reading::value::read_column_value;
+
+	use super::*;
+
+	#[test]
+	fn test_nested_specs() {
+		let bytes: &[u8] = &[
+			0, 1, b't', 0, 0x31, 0, 2,              // tuple<list<int>, map<text, int>>
+				0, 0x20, 0, 0x09,
+				0, 0x21, 0, 0x0D, 0, 0x09,
+			0, 1, b'u', 0, 0x30, 0, 2, b'k', b's', 0, 1, b'a', 0, 1,  // a<inner: frozen<b<x: tinyint>>>
+				0, 5, b'i', b'n', b'n', b'e', b'r',
+				0, 0x30, 0, 2, b'k', b's', 0, 1, b'b', 0, 1,
+					0, 1, b'x', 0, 0x14,
+			0, 1, b'n', 0, 0x09                     // int
+		];
+		let specs = read_column_specs(&mut &bytes[..], 3, true);
+
+		assert_eq!(specs.len(), 3);
+		assert_eq!(specs[2].name, "n");
+		match specs[1].collection_spec {
+			CollectionSpec::Udt(ref fields) => match fields[0].1.collection_spec {
+				CollectionSpec::Udt(ref inner) => assert_eq!(inner[0].0, "x"),
+				ref other => panic!("unexpected field spec {:?}", other)
+			},
+			ref other => panic!("unexpected spec {:?}", other)
 		}
-		_ => {}
+
+		let value: &[u8] = &[
+			0, 0, 0, 37,
+			0, 0, 0, 12, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 5,
+			0, 0, 0, 17, 0, 0, 0, 1, 0, 0, 0, 1, b'k', 0, 0, 0, 4, 0, 0, 0, 6
+		];
+		let column = read_column_value(&mut &value[..], ColumnType::Tuple, &specs[0].collection_spec);
+		assert_eq!(column, Column::Tuple(vec!(
+			Column::List(vec!(Column::Int(5))),
+			Column::Map(vec!((Column::String("k".to_string()), Column::Int(6))))
+		)));
 	}
-}
\ No newline at end of file
+}
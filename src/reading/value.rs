@@ -1,4 +1,7 @@
-use std::io::Read;
+use std::io::{
+	Read,
+	Cursor
+};
 
 use uuid::Uuid;
 
@@ -10,12 +13,13 @@ use byteorder::{
 use shared::{
 	ColumnType,
 	Column,
-	CollectionSpec
+	CollectionSpec,
+	ElementSpec
 };
 
 use reading::reader::read_fixed;
 
-pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec: CollectionSpec) -> Column {
+pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec: &CollectionSpec) -> Column {
 
 	let len = buf.read_i32::<BigEndian>().unwrap();
 	//println!("num of bytes for col {:?} is {}", data_type, len);
@@ -30,17 +34,25 @@ pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec:
 			Column::Float(buf.read_f32::<BigEndian>().unwrap()),
 		ColumnType::Double =>
 			Column::Double(buf.read_f64::<BigEndian>().unwrap()),
+		ColumnType::Tinyint =>
+			Column::Tinyint(buf.read_i8().unwrap()),
+		ColumnType::Smallint =>
+			Column::Smallint(buf.read_i16::<BigEndian>().unwrap()),
 		ColumnType::Int =>
 			Column::Int(buf.read_i32::<BigEndian>().unwrap()),
+		ColumnType::Boolean =>
+			Column::Boolean(buf.read_u8().unwrap() != 0),
 		ColumnType::Bigint =>
 			Column::Bigint(buf.read_i64::<BigEndian>().unwrap()),
-		ColumnType::Timeuuid => {
+		ColumnType::Uuid | ColumnType::Timeuuid => {
 			let bytes = read_fixed(buf, len as usize);
 			let uuid = Uuid::from_bytes(bytes.as_slice()).unwrap();
 			Column::String(uuid.hyphenated().to_string())
 		}
 		ColumnType::Timestamp =>
 			Column::Timestamp(buf.read_i64::<BigEndian>().unwrap()),
+		ColumnType::Blob | ColumnType::Inet =>
+			Column::Blob(read_fixed(buf, len as usize)),
 
 		ColumnType::Set => {
 
@@ -48,10 +60,10 @@ pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec:
 			//println!("set len is {}", set_len);
 
 			match collection_spec {
-				CollectionSpec::Set(set_column_type) => {
+				CollectionSpec::Set(ref element) => {
 					let mut set = vec!();
 					for i in 0..set_len {
-						set.push(read_collection_column_value(buf, set_column_type));
+						set.push(read_element_value(buf, element));
 						//println!("iterating over set, counter is {}", i);
 					}
 					//println!("set finished");
@@ -68,10 +80,10 @@ pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec:
 			//println!("list len is {}", list_len);
 
 			match collection_spec {
-				CollectionSpec::List(list_column_type) => {
+				CollectionSpec::List(ref element) => {
 					let mut list = vec!();
 						for i in 0..list_len {
-							list.push(read_collection_column_value(buf, list_column_type));
+							list.push(read_element_value(buf, element));
 							//println!("iterating over list, counter is {}", i);
 						}
 					Column::List(list)
@@ -87,14 +99,14 @@ pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec:
 			//println!("map len is {}", map_len);
 
 			match collection_spec {
-				CollectionSpec::Map(map_key_column_type, map_value_column_type) => {
+				CollectionSpec::Map(ref key_spec, ref value_spec) => {
 					let mut map = vec!();
 					let mut key: Column;
 					for i in 0..map_len {
-						key = read_collection_column_value(buf, map_key_column_type);
+						key = read_element_value(buf, key_spec);
 						match key {
 							Column::None => {},
-							_ => map.push((key.clone(), read_collection_column_value(buf, map_value_column_type)))
+							_ => map.push((key.clone(), read_element_value(buf, value_spec)))
 						}
 						//println!("iterating over map, counter is {}", i);
 					}
@@ -106,38 +118,51 @@ pub fn read_column_value(buf: &mut Read, data_type: ColumnType, collection_spec:
 
 		}
 
-		_ => {
-			let bytes = read_fixed(buf, len as usize);
-			Column::String(String::from_utf8(bytes).unwrap())
-		}
-	}
-}
+		ColumnType::Tuple => {
 
-fn read_collection_column_value(buf: &mut Read, data_type: ColumnType) -> Column {
+			match collection_spec {
+				CollectionSpec::Tuple(ref elements) => {
+					let mut tuple = vec!();
+					for element in elements.iter() {
+						tuple.push(read_element_value(buf, element));
+					}
+					Column::Tuple(tuple)
+				}
+				_ => Column::None
+			}
 
-	let len = buf.read_i32::<BigEndian>().unwrap();
-	//println!("num of bytes for col {:?} is {}", data_type, len);
+		}
 
-	if len < 0 {
-		//println!("read_column_value is returning without readind data (length was {})", len);
-		return Column::None
-	}
+		ColumnType::UDT => {
 
-	match data_type {
-		ColumnType::Float => Column::Float(buf.read_f32::<BigEndian>().unwrap()),
-		ColumnType::Double => Column::Double(buf.read_f64::<BigEndian>().unwrap()),
-		ColumnType::Int => Column::Int(buf.read_i32::<BigEndian>().unwrap()),
-		ColumnType::Bigint => Column::Bigint(buf.read_i64::<BigEndian>().unwrap()),
-		ColumnType::Timeuuid => {
+			// a value may omit trailing fields, so only read what the length covers
 			let bytes = read_fixed(buf, len as usize);
-			Column::String(Uuid::from_bytes(bytes.as_slice()).unwrap().hyphenated().to_string())
+			let mut cursor = Cursor::new(bytes);
+
+			match collection_spec {
+				CollectionSpec::Udt(ref field_specs) => {
+					let mut fields = vec!();
+					for &(ref name, ref field) in field_specs.iter() {
+						if cursor.position() >= len as u64 {
+							break;
+						}
+						fields.push((name.clone(), read_element_value(&mut cursor, field)));
+					}
+					Column::Udt(fields)
+				}
+				_ => Column::None
+			}
+
 		}
-		ColumnType::Timestamp =>
-			Column::Timestamp(buf.read_i64::<BigEndian>().unwrap()),
+
 		_ => {
 			let bytes = read_fixed(buf, len as usize);
 			Column::String(String::from_utf8(bytes).unwrap())
 		}
 	}
+}
 
-}
\ No newline at end of file
+/// Elements are read like columns, with their own nested specs.
+fn read_element_value(buf: &mut Read, element: &ElementSpec) -> Column {
+	read_column_value(buf, element.data_type, &element.collection_spec)
+}
@@ -0,0 +1,447 @@
+//! Optional serde support: `Row` and `Column` serialize to natural JSON, rows can be
+//! deserialized into any `Deserialize` type and `Serialize` structs can be turned
+//! into named bind values.
+
+use std::fmt::Display;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use serde::ser::{
+	self,
+	Serialize,
+	Serializer,
+	SerializeMap,
+	SerializeSeq
+};
+
+use serde::de::{
+	self,
+	Deserialize,
+	Deserializer,
+	DeserializeSeed,
+	IntoDeserializer,
+	MapAccess,
+	SeqAccess,
+	Visitor
+};
+
+use shared::{
+	Column,
+	Row
+};
+
+use mapping::MappingError;
+
+impl ser::Error for MappingError {
+	fn custom<T: Display>(msg: T) -> MappingError {
+		MappingError::Message(msg.to_string())
+	}
+}
+
+impl de::Error for MappingError {
+	fn custom<T: Display>(msg: T) -> MappingError {
+		MappingError::Message(msg.to_string())
+	}
+}
+
+/// Rows serialize as objects keyed by column name.
+impl Serialize for Row {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		let mut map = try!(serializer.serialize_map(Some(self.len())));
+		for (index, column) in self.columns.iter().enumerate() {
+			try!(map.serialize_entry(self.column_name(index), column));
+		}
+		map.end()
+	}
+}
+
+/// Maps and UDTs serialize as objects, blobs as base64 strings.
+impl Serialize for Column {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match *self {
+			Column::None | Column::Null | Column::Unset => serializer.serialize_none(),
+			Column::String(ref val) => serializer.serialize_str(val),
+			Column::Boolean(val) => serializer.serialize_bool(val),
+			Column::Blob(ref val) => serializer.serialize_str(&BASE64.encode(val)),
+			Column::Tinyint(val) => serializer.serialize_i8(val),
+			Column::Smallint(val) => serializer.serialize_i16(val),
+			Column::Int(val) => serializer.serialize_i32(val),
+			Column::Bigint(val) | Column::Timestamp(val) => serializer.serialize_i64(val),
+			Column::Float(val) => serializer.serialize_f32(val),
+			Column::Double(val) => serializer.serialize_f64(val),
+			Column::Set(ref vals) | Column::List(ref vals) | Column::Tuple(ref vals) => {
+				let mut seq = try!(serializer.serialize_seq(Some(vals.len())));
+				for val in vals.iter() {
+					try!(seq.serialize_element(val));
+				}
+				seq.end()
+			}
+			Column::Map(ref pairs) => {
+				let mut map = try!(serializer.serialize_map(Some(pairs.len())));
+				for (key, val) in pairs.iter() {
+					try!(map.serialize_entry(key, val));
+				}
+				map.end()
+			}
+			Column::Udt(ref fields) => {
+				let mut map = try!(serializer.serialize_map(Some(fields.len())));
+				for (name, val) in fields.iter() {
+					try!(map.serialize_entry(name, val));
+				}
+				map.end()
+			}
+		}
+	}
+}
+
+/// Deserializes a row into any `Deserialize` type. Structs and maps are filled by
+/// column name, tuples and sequences by position.
+pub fn from_row<'a, T: Deserialize<'a>>(row: &'a Row) -> Result<T, MappingError> {
+	T::deserialize(RowDeserializer { row: row })
+}
+
+/// Deserializes a single column value.
+pub fn from_column<'a, T: Deserialize<'a>>(column: &'a Column) -> Result<T, MappingError> {
+	T::deserialize(ColumnDeserializer { column: column })
+}
+
+/// Turns a `Serialize` struct (or map with string keys) into named bind values
+/// for `prm_query_with_names`.
+pub fn to_named_values<T: Serialize + ?Sized>(value: &T) -> Result<Vec<(String, Column)>, MappingError> {
+	match try!(to_column(value)) {
+		Column::Udt(fields) => Ok(fields),
+		Column::Map(pairs) => {
+			let mut named_values = Vec::with_capacity(pairs.len());
+			for (key, val) in pairs.into_iter() {
+				match key {
+					Column::String(name) => named_values.push((name, val)),
+					key => return Err(MappingError::mismatch("string", &key))
+				}
+			}
+			Ok(named_values)
+		}
+		column => Err(MappingError::mismatch("struct", &column))
+	}
+}
+
+/// Turns any `Serialize` value into a `Column`.
+pub fn to_column<T: Serialize + ?Sized>(value: &T) -> Result<Column, MappingError> {
+	value.serialize(ColumnSerializer)
+}
+
+struct RowDeserializer<'a> {
+	row: &'a Row
+}
+
+impl<'de> Deserializer<'de> for RowDeserializer<'de> {
+	type Error = MappingError;
+
+	fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MappingError> {
+		let row = self.row;
+		visitor.visit_map(PairsAccess {
+			pairs: row.columns.iter().enumerate().map(move |(index, column)| (row.column_name(index), column)),
+			value: None
+		})
+	}
+
+	fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MappingError> {
+		visitor.visit_seq(ColumnsAccess { columns: self.row.columns.iter() })
+	}
+
+	fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, MappingError> {
+		self.deserialize_seq(visitor)
+	}
+
+	fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize, visitor: V) -> Result<V::Value, MappingError> {
+		self.deserialize_seq(visitor)
+	}
+
+	forward_to_deserialize_any! {
+		bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+		bytes byte_buf option unit unit_struct newtype_struct map struct enum
+		identifier ignored_any
+	}
+}
+
+/// Deserializer over a borrowed `Column`, see `from_column`.
+pub struct ColumnDeserializer<'a> {
+	column: &'a Column
+}
+
+impl<'de> Deserializer<'de> for ColumnDeserializer<'de> {
+	type Error = MappingError;
+
+	fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MappingError> {
+		match *self.column {
+			Column::None | Column::Null | Column::Unset => visitor.visit_unit(),
+			Column::String(ref val) => visitor.visit_borrowed_str(val),
+			Column::Boolean(val) => visitor.visit_bool(val),
+			Column::Blob(ref val) => visitor.visit_borrowed_bytes(val),
+			Column::Tinyint(val) => visitor.visit_i8(val),
+			Column::Smallint(val) => visitor.visit_i16(val),
+			Column::Int(val) => visitor.visit_i32(val),
+			Column::Bigint(val) | Column::Timestamp(val) => visitor.visit_i64(val),
+			Column::Float(val) => visitor.visit_f32(val),
+			Column::Double(val) => visitor.visit_f64(val),
+			Column::Set(ref vals) | Column::List(ref vals) | Column::Tuple(ref vals) =>
+				visitor.visit_seq(ColumnsAccess { columns: vals.iter() }),
+			Column::Map(ref pairs) =>
+				visitor.visit_map(PairsAccess { pairs: pairs.iter().map(|(key, val)| (key, val)), value: None }),
+			Column::Udt(ref fields) =>
+				visitor.visit_map(PairsAccess { pairs: fields.iter().map(|(name, val)| (name.as_str(), val)), value: None })
+		}
+	}
+
+	fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MappingError> {
+		match *self.column {
+			Column::None | Column::Null | Column::Unset => visitor.visit_none(),
+			_ => visitor.visit_some(self)
+		}
+	}
+
+	fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MappingError> {
+		match *self.column {
+			Column::Blob(ref val) => visitor.visit_seq(de::value::SeqDeserializer::new(val.iter().cloned())),
+			_ => self.deserialize_any(visitor)
+		}
+	}
+
+	fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, MappingError> {
+		visitor.visit_newtype_struct(self)
+	}
+
+	fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, MappingError> {
+		match *self.column {
+			Column::String(ref val) => visitor.visit_enum(val.as_str().into_deserializer()),
+			_ => Err(MappingError::mismatch("string", self.column))
+		}
+	}
+
+	forward_to_deserialize_any! {
+		bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+		bytes byte_buf unit unit_struct tuple tuple_struct map struct
+		identifier ignored_any
+	}
+}
+
+struct ColumnsAccess<I> {
+	columns: I
+}
+
+impl<'de, I: Iterator<Item = &'de Column>> SeqAccess<'de> for ColumnsAccess<I> {
+	type Error = MappingError;
+
+	fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, MappingError> {
+		match self.columns.next() {
+			Some(column) => seed.deserialize(ColumnDeserializer { column: column }).map(Some),
+			None => Ok(None)
+		}
+	}
+}
+
+struct PairsAccess<'de, I> {
+	pairs: I,
+	value: Option<&'de Column>
+}
+
+impl<'de, I, K> MapAccess<'de> for PairsAccess<'de, I>
+	where I: Iterator<Item = (K, &'de Column)>, K: IntoDeserializer<'de, MappingError> {
+	type Error = MappingError;
+
+	fn next_key_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, MappingError> {
+		match self.pairs.next() {
+			Some((key, value)) => {
+				self.value = Some(value);
+				seed.deserialize(key.into_deserializer()).map(Some)
+			}
+			None => Ok(None)
+		}
+	}
+
+	fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, MappingError> {
+		match self.value.take() {
+			Some(column) => seed.deserialize(ColumnDeserializer { column: column }),
+			None => Err(MappingError::Message("value requested before key".to_string()))
+		}
+	}
+}
+
+impl<'de> IntoDeserializer<'de, MappingError> for &'de Column {
+	type Deserializer = ColumnDeserializer<'de>;
+
+	fn into_deserializer(self) -> ColumnDeserializer<'de> {
+		ColumnDeserializer { column: self }
+	}
+}
+
+struct ColumnSerializer;
+
+impl Serializer for ColumnSerializer {
+	type Ok = Column;
+	type Error = MappingError;
+
+	type SerializeSeq = SeqSerializer;
+	type SerializeTuple = SeqSerializer;
+	type SerializeTupleStruct = SeqSerializer;
+	type SerializeTupleVariant = ser::Impossible<Column, MappingError>;
+	type SerializeMap = MapSerializer;
+	type SerializeStruct = StructSerializer;
+	type SerializeStructVariant = ser::Impossible<Column, MappingError>;
+
+	fn serialize_bool(self, v: bool) -> Result<Column, MappingError> { Ok(Column::Boolean(v)) }
+	fn serialize_i8(self, v: i8) -> Result<Column, MappingError> { Ok(Column::Tinyint(v)) }
+	fn serialize_i16(self, v: i16) -> Result<Column, MappingError> { Ok(Column::Smallint(v)) }
+	fn serialize_i32(self, v: i32) -> Result<Column, MappingError> { Ok(Column::Int(v)) }
+	fn serialize_i64(self, v: i64) -> Result<Column, MappingError> { Ok(Column::Bigint(v)) }
+	fn serialize_u8(self, v: u8) -> Result<Column, MappingError> { Ok(Column::Int(v as i32)) }
+	fn serialize_u16(self, v: u16) -> Result<Column, MappingError> { Ok(Column::Int(v as i32)) }
+	fn serialize_u32(self, v: u32) -> Result<Column, MappingError> { Ok(Column::Bigint(v as i64)) }
+	fn serialize_u64(self, v: u64) -> Result<Column, MappingError> {
+		if v > i64::MAX as u64 {
+			return Err(MappingError::Message(format!("{} does not fit into a bigint", v)));
+		}
+		Ok(Column::Bigint(v as i64))
+	}
+	fn serialize_f32(self, v: f32) -> Result<Column, MappingError> { Ok(Column::Float(v)) }
+	fn serialize_f64(self, v: f64) -> Result<Column, MappingError> { Ok(Column::Double(v)) }
+	fn serialize_char(self, v: char) -> Result<Column, MappingError> { Ok(Column::String(v.to_string())) }
+	fn serialize_str(self, v: &str) -> Result<Column, MappingError> { Ok(Column::String(v.to_string())) }
+	fn serialize_bytes(self, v: &[u8]) -> Result<Column, MappingError> { Ok(Column::Blob(v.to_vec())) }
+	fn serialize_none(self) -> Result<Column, MappingError> { Ok(Column::None) }
+	fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Column, MappingError> { value.serialize(self) }
+	fn serialize_unit(self) -> Result<Column, MappingError> { Ok(Column::None) }
+	fn serialize_unit_struct(self, _name: &'static str) -> Result<Column, MappingError> { Ok(Column::None) }
+
+	fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<Column, MappingError> {
+		Ok(Column::String(variant.to_string()))
+	}
+
+	fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<Column, MappingError> {
+		value.serialize(self)
+	}
+
+	fn serialize_newtype_variant<T: Serialize + ?Sized>(self, name: &'static str, _index: u32, variant: &'static str, _value: &T) -> Result<Column, MappingError> {
+		Err(MappingError::Message(format!("cannot bind enum variant {}::{}", name, variant)))
+	}
+
+	fn serialize_seq(self, len: Option<usize>) -> Result<SeqSerializer, MappingError> {
+		Ok(SeqSerializer { values: Vec::with_capacity(len.unwrap_or(0)), tuple: false })
+	}
+
+	fn serialize_tuple(self, len: usize) -> Result<SeqSerializer, MappingError> {
+		Ok(SeqSerializer { values: Vec::with_capacity(len), tuple: true })
+	}
+
+	fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqSerializer, MappingError> {
+		self.serialize_tuple(len)
+	}
+
+	fn serialize_tuple_variant(self, name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant, MappingError> {
+		Err(MappingError::Message(format!("cannot bind enum variant {}::{}", name, variant)))
+	}
+
+	fn serialize_map(self, len: Option<usize>) -> Result<MapSerializer, MappingError> {
+		Ok(MapSerializer { pairs: Vec::with_capacity(len.unwrap_or(0)), key: None })
+	}
+
+	fn serialize_struct(self, _name: &'static str, len: usize) -> Result<StructSerializer, MappingError> {
+		Ok(StructSerializer { fields: Vec::with_capacity(len) })
+	}
+
+	fn serialize_struct_variant(self, name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant, MappingError> {
+		Err(MappingError::Message(format!("cannot bind enum variant {}::{}", name, variant)))
+	}
+}
+
+struct SeqSerializer {
+	values: Vec<Column>,
+	tuple: bool
+}
+
+impl SerializeSeq for SeqSerializer {
+	type Ok = Column;
+	type Error = MappingError;
+
+	fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MappingError> {
+		self.values.push(try!(to_column(value)));
+		Ok(())
+	}
+
+	fn end(self) -> Result<Column, MappingError> {
+		Ok(if self.tuple { Column::Tuple(self.values) } else { Column::List(self.values) })
+	}
+}
+
+impl ser::SerializeTuple for SeqSerializer {
+	type Ok = Column;
+	type Error = MappingError;
+
+	fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MappingError> {
+		SerializeSeq::serialize_element(self, value)
+	}
+
+	fn end(self) -> Result<Column, MappingError> {
+		SerializeSeq::end(self)
+	}
+}
+
+impl ser::SerializeTupleStruct for SeqSerializer {
+	type Ok = Column;
+	type Error = MappingError;
+
+	fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MappingError> {
+		SerializeSeq::serialize_element(self, value)
+	}
+
+	fn end(self) -> Result<Column, MappingError> {
+		SerializeSeq::end(self)
+	}
+}
+
+struct MapSerializer {
+	pairs: Vec<(Column, Column)>,
+	key: Option<Column>
+}
+
+impl SerializeMap for MapSerializer {
+	type Ok = Column;
+	type Error = MappingError;
+
+	fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), MappingError> {
+		self.key = Some(try!(to_column(key)));
+		Ok(())
+	}
+
+	fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MappingError> {
+		match self.key.take() {
+			Some(key) => {
+				self.pairs.push((key, try!(to_column(value))));
+				Ok(())
+			}
+			None => Err(MappingError::Message("map value serialized before key".to_string()))
+		}
+	}
+
+	fn end(self) -> Result<Column, MappingError> {
+		Ok(Column::Map(self.pairs))
+	}
+}
+
+struct StructSerializer {
+	fields: Vec<(String, Column)>
+}
+
+impl ser::SerializeStruct for StructSerializer {
+	type Ok = Column;
+	type Error = MappingError;
+
+	fn serialize_field<T: Serialize + ?Sized>(&mut self, name: &'static str, value: &T) -> Result<(), MappingError> {
+		self.fields.push((name.to_string(), try!(to_column(value))));
+		Ok(())
+	}
+
+	fn end(self) -> Result<Column, MappingError> {
+		Ok(Column::Udt(self.fields))
+	}
+}
@@ -1,10 +1,72 @@
 use std::collections::HashMap;
+use std::result;
+use std::sync::Arc;
 use core::cmp::PartialEq;
 
+use uuid::Uuid;
+
+use mapping::{
+	FromCql,
+	MappingError
+};
+
+use statement::{
+	Statement,
+	Target,
+	Batch
+};
+
 
 
 pub static CQL_BINARY_PROTOCOL_VERSION:u8 = 0x04;
 
+/// Compression of protocol v5 segments, asked for at startup.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "lz4")]
+    Lz4
+}
+
+/// Native protocol versions this driver speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    V3 = 0x03,
+    V4 = 0x04,
+    V5 = 0x05
+}
+
+impl ProtocolVersion {
+    /// Where `connect` starts negotiating.
+    pub fn highest() -> ProtocolVersion {
+        ProtocolVersion::V5
+    }
+
+    /// From v5 on, frames after the handshake travel in checksummed segments.
+    pub fn has_segments(self) -> bool {
+        self >= ProtocolVersion::V5
+    }
+
+    /// Version of a frame header, ignoring the response direction bit.
+    pub fn from_u8(value: u8) -> Option<ProtocolVersion> {
+        match value & 0x7F {
+            0x03 => Some(ProtocolVersion::V3),
+            0x04 => Some(ProtocolVersion::V4),
+            0x05 => Some(ProtocolVersion::V5),
+            _ => None
+        }
+    }
+
+    /// The version to fall back to when the server rejects this one.
+    pub fn lower(self) -> Option<ProtocolVersion> {
+        match self {
+            ProtocolVersion::V5 => Some(ProtocolVersion::V4),
+            ProtocolVersion::V4 => Some(ProtocolVersion::V3),
+            ProtocolVersion::V3 => None
+        }
+    }
+}
+
 
 
 pub enum Opcode {
@@ -82,7 +144,8 @@ pub enum RowsFlag {
     None = 0x0000,
     GlobalTablesSpec = 0x0001,
     HasMorePages = 0x0002,
-    NoMetadata = 0x0004
+    NoMetadata = 0x0004,
+    MetadataChanged = 0x0008
 }
 
 pub enum QueryFlag {
@@ -93,10 +156,12 @@ pub enum QueryFlag {
 	WithPagingState = 0x08,
 	WithSerialConsistency = 0x10,
 	WithDefaultTimestamp = 0x20,
-	WithNamesForValues = 0x40
+	WithNamesForValues = 0x40,
+	WithKeyspace = 0x80,
+	WithNowInSeconds = 0x100
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Consistency {
 	Any = 0x0000,
 	One = 0x0001,
@@ -112,6 +177,7 @@ pub enum Consistency {
 	Unknown
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BatchType {
 	Logged = 0x00,
 	Unlogged = 0x01,
@@ -122,7 +188,9 @@ pub enum BatchFlag {
 	None = 0x00,
 	WithSerialConsistency = 0x10,
 	WithDefaultTimestamp = 0x20,
-	WithNamesForValues = 0x40
+	WithNamesForValues = 0x40,
+	WithKeyspace = 0x80,
+	WithNowInSeconds = 0x100
 }
 
 pub enum BatchQueryKind {
@@ -140,17 +208,9 @@ pub enum BatchQuery {
 pub enum Request {
 	Startup(HashMap<String, String>),
   	Options,
-  	Query(String, Consistency),
-  	PrmQuery(String, Vec<Column>, Consistency),
-    PrmQueryWithNames(String, Vec<(String, Column)>, Consistency),
  	Prepare(String),
-	Execute(Vec<u8>, Vec<Column>, Consistency),
-	Batch(Vec<BatchQuery>, Consistency),
-
-    PagedQuery(String, Consistency, i32, Option<Vec<u8>>),
-    PagedPrmQuery(String, Vec<Column>, Consistency, i32, Option<Vec<u8>>),
-    PagedPrmQueryWithNames(String, Vec<(String, Column)>, Consistency, i32, Option<Vec<u8>>),
-    PagedExecute(Vec<u8>, Vec<Column>, Consistency, i32, Option<Vec<u8>>)
+	Statement(Statement),
+	Batch(Batch)
 }
 
 impl Request {
@@ -158,14 +218,52 @@ impl Request {
     match *self {
       	Request::Startup(_) => 0x01,
       	Request::Options => 0x05,
-      	Request::Query(_, _) | Request::PrmQuery(_, _, _) | Request::PrmQueryWithNames(_, _, _) |
-        Request::PagedQuery(_, _, _, _) | Request::PagedPrmQuery(_, _, _, _, _) | Request::PagedPrmQueryWithNames(_, _, _, _, _)
-        => 0x07,
 		Request::Prepare(_) => 0x09,
-		Request::Execute(_, _, _) | Request::PagedExecute(_, _, _, _, _) => 0x0A,
-		Request::Batch(_, _) => 0x0D
+		Request::Statement(ref statement) => match statement.target {
+			Target::Query(_) => 0x07,
+			Target::Prepared(_) => 0x0A
+		},
+		Request::Batch(..) => 0x0D
+    }
+  }
+  pub fn flags(&self) -> u8 {
+    let (tracing, custom_payload) = match *self {
+		Request::Statement(ref statement) => (statement.tracing, !statement.custom_payload.is_empty()),
+		Request::Batch(ref batch) => (batch.tracing, !batch.custom_payload.is_empty()),
+		_ => (false, false)
+    };
+    let mut flags = FrameFlag::None as u8;
+    if tracing {
+		flags = flags | FrameFlag::Tracing as u8;
+    }
+    if custom_payload {
+		flags = flags | FrameFlag::CustomPayload as u8;
     }
+    flags
   }
+  /// The custom payload to write before the body, if any.
+  pub fn custom_payload(&self) -> Option<&HashMap<String, Vec<u8>>> {
+    match *self {
+		Request::Statement(ref statement) if !statement.custom_payload.is_empty() => Some(&statement.custom_payload),
+		Request::Batch(ref batch) if !batch.custom_payload.is_empty() => Some(&batch.custom_payload),
+		_ => None
+    }
+  }
+}
+
+/// A response together with what the frame carried besides the body.
+#[derive(Debug)]
+pub struct Envelope {
+  /// Set when the request was sent with tracing, see `Connection::trace`.
+  pub tracing_id: Option<Uuid>,
+  /// Warnings the server attached, e.g. about large batches or tombstones.
+  pub warnings: Vec<String>,
+  /// Custom payload set by a server-side query handler.
+  pub custom_payload: HashMap<String, Vec<u8>>,
+  /// The new result metadata id and metadata of the executed prepared
+  /// statement, when the server says they changed (v5).
+  pub metadata_changed: Option<(Vec<u8>, Arc<Vec<ColumnSpec>>)>,
+  pub response: Response
 }
 
 #[derive(Debug)]
@@ -184,28 +282,84 @@ pub enum ResultBody {
   Void,
   Rows(Vec<Row>, Option<Vec<u8>>),
   SetKeyspace(String),
-  Prepared(Vec<u8>),
+  /// id, result metadata, and from v5 on the result metadata id
+  Prepared(Vec<u8>, Arc<Vec<ColumnSpec>>, Option<Vec<u8>>),
   SchemaChange(String, String, String)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ColumnSpec {
 	pub name: String,
 	pub data_type: ColumnType,
 	pub collection_spec: CollectionSpec
 }
 
+/// Type of a collection element, tuple element or UDT field, which may
+/// itself be a collection, tuple or UDT.
+#[derive(Debug, Clone)]
+pub struct ElementSpec {
+	pub data_type: ColumnType,
+	pub collection_spec: CollectionSpec
+}
+
 #[derive(Debug, Clone)]
 pub enum CollectionSpec {
 	None,
-	Set(ColumnType),
-	List(ColumnType),
-	Map(ColumnType, ColumnType)
+	Set(Box<ElementSpec>),
+	List(Box<ElementSpec>),
+	Map(Box<ElementSpec>, Box<ElementSpec>),
+	Tuple(Vec<ElementSpec>),
+	Udt(Vec<(String, ElementSpec)>)
 }
 
-#[derive(Debug)]
+/// A single result row. Values are kept in select-list order, the column
+/// metadata is shared between all rows of the same page.
+#[derive(Debug, Clone)]
 pub struct Row {
-  pub columns: HashMap<String, Column>
+  pub specs: Arc<Vec<ColumnSpec>>,
+  pub columns: Vec<Column>
+}
+
+impl Row {
+	pub fn get(&self, index: usize) -> Option<&Column> {
+		self.columns.get(index)
+	}
+	/// Converts the column at `index` into a Rust value.
+	pub fn get_as<T: FromCql>(&self, index: usize) -> result::Result<T, MappingError> {
+		match self.columns.get(index) {
+			Some(column) => T::from_cql(column).map_err(|e| e.in_column(self.column_name(index))),
+			None => Err(MappingError::MissingColumn(index.to_string()))
+		}
+	}
+	/// Converts the column called `name` into a Rust value.
+	pub fn get_by_name_as<T: FromCql>(&self, name: &str) -> result::Result<T, MappingError> {
+		match self.index_of(name) {
+			Some(index) => self.get_as(index),
+			None => Err(MappingError::MissingColumn(name.to_string()))
+		}
+	}
+	pub fn column_name(&self, index: usize) -> &str {
+		match self.specs.get(index) {
+			Some(spec) => &spec.name,
+			None => ""
+		}
+	}
+	/// Returns the first column with the given name (or alias).
+	pub fn get_by_name(&self, name: &str) -> Option<&Column> {
+		match self.index_of(name) {
+			Some(index) => self.columns.get(index),
+			None => None
+		}
+	}
+	pub fn index_of(&self, name: &str) -> Option<usize> {
+		self.specs.iter().position(|spec| spec.name == name)
+	}
+	pub fn len(&self) -> usize {
+		self.columns.len()
+	}
+	pub fn is_empty(&self) -> bool {
+		self.columns.is_empty()
+	}
 }
 
 #[derive(Copy, Debug, Clone)]
@@ -227,6 +381,8 @@ pub enum ColumnType {
 	Varint = 0x000E,
 	Timeuuid = 0x000F,
 	Inet = 0x0010,
+	Smallint = 0x0013,
+	Tinyint = 0x0014,
 	List = 0x0020,
 	Map = 0x0021,
 	Set = 0x0022,
@@ -253,6 +409,8 @@ pub fn to_column_type(value: u16) -> ColumnType {
 		0x000E => ColumnType::Varint,
 		0x000F => ColumnType::Timeuuid,
 		0x0010 => ColumnType::Inet,
+		0x0013 => ColumnType::Smallint,
+		0x0014 => ColumnType::Tinyint,
 		0x0020 => ColumnType::List,
 		0x0021 => ColumnType::Map,
 		0x0022 => ColumnType::Set,
@@ -264,8 +422,18 @@ pub fn to_column_type(value: u16) -> ColumnType {
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Column {
+	/// A null read from the server. Bound as a parameter it is written as null.
 	None,
+	/// An explicit null parameter, which deletes the column it is bound to.
+	Null,
+	/// A parameter left unset (protocol v4 and later), so the column is not
+	/// touched and no tombstone is written.
+	Unset,
 	String(String),
+	Boolean(bool),
+	Blob(Vec<u8>),
+	Tinyint(i8),
+	Smallint(i16),
 	Int(i32),
 	Bigint(i64),
 	Float(f32),
@@ -273,10 +441,34 @@ pub enum Column {
 	Timestamp(i64),
 	Set(Vec<Column>),
 	List(Vec<Column>),
-	Map(Vec<(Column, Column)>)
+	Map(Vec<(Column, Column)>),
+	Tuple(Vec<Column>),
+	Udt(Vec<(String, Column)>)
 }
 
 impl Column {
+	/// Short name of the variant, used in mapping errors.
+	pub fn type_name(&self) -> &'static str {
+		match *self {
+			Column::None | Column::Null => "null",
+			Column::Unset => "unset",
+			Column::String(_) => "string",
+			Column::Boolean(_) => "boolean",
+			Column::Blob(_) => "blob",
+			Column::Tinyint(_) => "tinyint",
+			Column::Smallint(_) => "smallint",
+			Column::Int(_) => "int",
+			Column::Bigint(_) => "bigint",
+			Column::Float(_) => "float",
+			Column::Double(_) => "double",
+			Column::Timestamp(_) => "timestamp",
+			Column::Set(_) => "set",
+			Column::List(_) => "list",
+			Column::Map(_) => "map",
+			Column::Tuple(_) => "tuple",
+			Column::Udt(_) => "udt"
+		}
+	}
 	pub fn get_string(&self) -> Option<String> {
 		match *self {
 			Column::String(ref val) => Some(val.clone()),
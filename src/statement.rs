@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+
+use shared::{
+	Consistency,
+	Column,
+	BatchType,
+	BatchQuery
+};
+
+use mapping::{
+	IntoValues,
+	IntoNamedValues
+};
+
+/// What a statement runs: CQL text (QUERY) or the id of a prepared statement (EXECUTE).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+	Query(String),
+	Prepared(Vec<u8>)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Values {
+	None,
+	Positional(Vec<Column>),
+	Named(Vec<(String, Column)>)
+}
+
+/// A QUERY or EXECUTE request together with all of its query parameters.
+///
+/// ```ignore
+/// let statement = Statement::query("SELECT * FROM testing.users WHERE user_id = ?")
+///     .values(("jsmith",))
+///     .consistency(Consistency::LocalQuorum)
+///     .page_size(100);
+/// let response = conn.execute(statement);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Statement {
+	pub target: Target,
+	pub consistency: Consistency,
+	pub values: Values,
+	pub page_size: Option<i32>,
+	pub paging_state: Option<Vec<u8>>,
+	pub skip_metadata: bool,
+	pub serial_consistency: Option<Consistency>,
+	pub default_timestamp: Option<i64>,
+	pub tracing: bool,
+	pub custom_payload: HashMap<String, Vec<u8>>,
+	/// Keyspace to run in instead of the connection's, needs protocol v5.
+	pub keyspace: Option<String>,
+	/// The server's "now" in seconds since the epoch, for TTLs, needs protocol v5.
+	pub now_in_seconds: Option<i32>,
+	/// Sent with EXECUTE from protocol v5 on, filled in by the connection.
+	pub result_metadata_id: Option<Vec<u8>>
+}
+
+impl Statement {
+	pub fn query<S: Into<String>>(query: S) -> Statement {
+		Statement::new(Target::Query(query.into()))
+	}
+
+	pub fn prepared(id: Vec<u8>) -> Statement {
+		Statement::new(Target::Prepared(id))
+	}
+
+	fn new(target: Target) -> Statement {
+		Statement {
+			target: target,
+			consistency: Consistency::One,
+			values: Values::None,
+			page_size: None,
+			paging_state: None,
+			skip_metadata: false,
+			serial_consistency: None,
+			default_timestamp: None,
+			tracing: false,
+			custom_payload: HashMap::new(),
+			keyspace: None,
+			now_in_seconds: None,
+			result_metadata_id: None
+		}
+	}
+
+	pub fn consistency(mut self, consistency: Consistency) -> Statement {
+		self.consistency = consistency;
+		self
+	}
+
+	pub fn values<V: IntoValues>(mut self, values: V) -> Statement {
+		self.values = Values::Positional(values.into_values());
+		self
+	}
+
+	pub fn named_values<N: IntoNamedValues>(mut self, named_values: N) -> Statement {
+		self.values = Values::Named(named_values.into_named_values());
+		self
+	}
+
+	pub fn page_size(mut self, page_size: i32) -> Statement {
+		self.page_size = Some(page_size);
+		self
+	}
+
+	pub fn paging_state(mut self, paging_state: Option<Vec<u8>>) -> Statement {
+		self.paging_state = paging_state;
+		self
+	}
+
+	/// Asks the server to leave out result metadata. Only applies to prepared
+	/// statements, whose result metadata the connection remembers from `prepare`.
+	pub fn skip_metadata(mut self, skip_metadata: bool) -> Statement {
+		self.skip_metadata = skip_metadata;
+		self
+	}
+
+	/// Consistency of the Paxos phase of conditional updates, `Serial` or `LocalSerial`.
+	pub fn serial_consistency(mut self, serial_consistency: Consistency) -> Statement {
+		self.serial_consistency = Some(serial_consistency);
+		self
+	}
+
+	/// Write timestamp in microseconds since the epoch, instead of the server's clock.
+	pub fn default_timestamp(mut self, default_timestamp: i64) -> Statement {
+		self.default_timestamp = Some(default_timestamp);
+		self
+	}
+
+	pub fn tracing(mut self, tracing: bool) -> Statement {
+		self.tracing = tracing;
+		self
+	}
+
+	/// Adds an entry to the custom payload, for a custom query handler on the server.
+	pub fn payload<S: Into<String>>(mut self, key: S, value: Vec<u8>) -> Statement {
+		self.custom_payload.insert(key.into(), value);
+		self
+	}
+
+	pub fn keyspace<S: Into<String>>(mut self, keyspace: S) -> Statement {
+		self.keyspace = Some(keyspace.into());
+		self
+	}
+
+	pub fn now_in_seconds(mut self, now_in_seconds: i32) -> Statement {
+		self.now_in_seconds = Some(now_in_seconds);
+		self
+	}
+}
+
+/// A BATCH request: several simple and/or prepared statements applied together.
+///
+/// ```ignore
+/// let batch = Batch::unlogged()
+///     .query_with("INSERT INTO testing.users (user_id, first_name) VALUES (?, ?)", ("jsmith", "John"))
+///     .prepared(id, ("jdoe", "Jane"))
+///     .consistency(Consistency::Quorum);
+/// let response = conn.batch(batch);
+/// ```
+///
+/// Named values apply to the whole batch: either every statement with values
+/// uses names or none does.
+#[derive(Debug, Clone)]
+pub struct Batch {
+	pub batch_type: BatchType,
+	pub statements: Vec<(Target, Values)>,
+	pub consistency: Consistency,
+	pub serial_consistency: Option<Consistency>,
+	pub default_timestamp: Option<i64>,
+	pub tracing: bool,
+	pub custom_payload: HashMap<String, Vec<u8>>,
+	/// Keyspace to run in instead of the connection's, needs protocol v5.
+	pub keyspace: Option<String>,
+	/// The server's "now" in seconds since the epoch, for TTLs, needs protocol v5.
+	pub now_in_seconds: Option<i32>
+}
+
+impl Batch {
+	pub fn new(batch_type: BatchType) -> Batch {
+		Batch {
+			batch_type: batch_type,
+			statements: vec!(),
+			consistency: Consistency::One,
+			serial_consistency: None,
+			default_timestamp: None,
+			tracing: false,
+			custom_payload: HashMap::new(),
+			keyspace: None,
+			now_in_seconds: None
+		}
+	}
+
+	pub fn logged() -> Batch {
+		Batch::new(BatchType::Logged)
+	}
+
+	/// Skips the batch log, for writes to a single partition.
+	pub fn unlogged() -> Batch {
+		Batch::new(BatchType::Unlogged)
+	}
+
+	/// For counter updates only.
+	pub fn counter() -> Batch {
+		Batch::new(BatchType::Counter)
+	}
+
+	pub fn query<S: Into<String>>(mut self, query: S) -> Batch {
+		self.statements.push((Target::Query(query.into()), Values::None));
+		self
+	}
+
+	pub fn query_with<S: Into<String>, V: IntoValues>(mut self, query: S, values: V) -> Batch {
+		self.statements.push((Target::Query(query.into()), Values::Positional(values.into_values())));
+		self
+	}
+
+	pub fn query_with_names<S: Into<String>, N: IntoNamedValues>(mut self, query: S, named_values: N) -> Batch {
+		self.statements.push((Target::Query(query.into()), Values::Named(named_values.into_named_values())));
+		self
+	}
+
+	pub fn prepared<V: IntoValues>(mut self, id: Vec<u8>, values: V) -> Batch {
+		self.statements.push((Target::Prepared(id), Values::Positional(values.into_values())));
+		self
+	}
+
+	pub fn prepared_with_names<N: IntoNamedValues>(mut self, id: Vec<u8>, named_values: N) -> Batch {
+		self.statements.push((Target::Prepared(id), Values::Named(named_values.into_named_values())));
+		self
+	}
+
+	/// Adds the target and values of `statement`, its other options don't apply in a batch.
+	pub fn statement(mut self, statement: Statement) -> Batch {
+		self.statements.push((statement.target, statement.values));
+		self
+	}
+
+	pub fn consistency(mut self, consistency: Consistency) -> Batch {
+		self.consistency = consistency;
+		self
+	}
+
+	/// Consistency of the Paxos phase of conditional updates, `Serial` or `LocalSerial`.
+	pub fn serial_consistency(mut self, serial_consistency: Consistency) -> Batch {
+		self.serial_consistency = Some(serial_consistency);
+		self
+	}
+
+	/// Write timestamp in microseconds since the epoch, instead of the server's clock.
+	pub fn default_timestamp(mut self, default_timestamp: i64) -> Batch {
+		self.default_timestamp = Some(default_timestamp);
+		self
+	}
+
+	pub fn tracing(mut self, tracing: bool) -> Batch {
+		self.tracing = tracing;
+		self
+	}
+
+	/// Adds an entry to the custom payload, for a custom query handler on the server.
+	pub fn payload<S: Into<String>>(mut self, key: S, value: Vec<u8>) -> Batch {
+		self.custom_payload.insert(key.into(), value);
+		self
+	}
+
+	pub fn keyspace<S: Into<String>>(mut self, keyspace: S) -> Batch {
+		self.keyspace = Some(keyspace.into());
+		self
+	}
+
+	pub fn now_in_seconds(mut self, now_in_seconds: i32) -> Batch {
+		self.now_in_seconds = Some(now_in_seconds);
+		self
+	}
+
+	/// Whether the statements' values are sent with names.
+	pub fn with_names(&self) -> bool {
+		self.statements.iter().any(|&(_, ref values)| match *values {
+			Values::Named(_) => true,
+			_ => false
+		})
+	}
+}
+
+impl From<BatchQuery> for (Target, Values) {
+	fn from(query: BatchQuery) -> (Target, Values) {
+		match query {
+			BatchQuery::Simple(query) => (Target::Query(query), Values::None),
+			BatchQuery::SimpleWithParams(query, values) => (Target::Query(query), Values::Positional(values)),
+			BatchQuery::Prepared(id, values) => (Target::Prepared(id), Values::Positional(values))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use shared::{
+		Consistency,
+		Request,
+		ProtocolVersion
+	};
+
+	use writing::WriteMessage;
+
+	use super::*;
+
+	#[test]
+	fn test_query_parameters() {
+		let statement = Statement::query("SELECT")
+			.values((1,))
+			.consistency(Consistency::Quorum)
+			.page_size(10)
+			.paging_state(Some(vec![7]))
+			.serial_consistency(Consistency::LocalSerial)
+			.default_timestamp(42);
+
+		let mut frame = Vec::new();
+		frame.write_message(Request::Statement(statement)).unwrap();
+
+		let body = &frame[9..];
+		assert_eq!(body, &[
+			0, 0, 0, 6, b'S', b'E', b'L', b'E', b'C', b'T',
+			0, 4,                          // consistency
+			0x01 | 0x04 | 0x08 | 0x10 | 0x20,
+			0, 1, 0, 0, 0, 4, 0, 0, 0, 1,  // values
+			0, 0, 0, 10,                   // page size
+			0, 0, 0, 1, 7,                 // paging state
+			0, 9,                          // serial consistency
+			0, 0, 0, 0, 0, 0, 0, 42        // timestamp
+		][..]);
+	}
+
+	#[test]
+	fn test_custom_payload() {
+		let statement = Statement::query("S").payload("tenant", vec!(3));
+
+		let mut frame = Vec::new();
+		frame.write_message(Request::Statement(statement)).unwrap();
+
+		assert_eq!(frame[1], 0x04);
+		assert_eq!(&frame[9..], &[
+			0, 1, 0, 6, b't', b'e', b'n', b'a', b'n', b't', 0, 0, 0, 1, 3,
+			0, 0, 0, 1, b'S',
+			0, 1, 0
+		][..]);
+	}
+
+	#[test]
+	fn test_versions() {
+		let statement = Statement::prepared(vec!(5)).keyspace("ks").now_in_seconds(7);
+		let mut frame = Vec::new();
+		frame.write_message_with_version(Request::Statement(statement.clone()), ProtocolVersion::V5).unwrap();
+
+		assert_eq!(frame[0], 0x05);
+		assert_eq!(&frame[9..], &[
+			0, 1, 5,                       // id
+			0, 0,                          // no result metadata id known
+			0, 1,                          // consistency
+			0, 0, 0x01, 0x80,              // keyspace and now_in_seconds flags as an int
+			0, 2, b'k', b's',
+			0, 0, 0, 7
+		][..]);
+
+		assert!(Vec::new().write_message_with_version(Request::Statement(statement), ProtocolVersion::V4).is_err());
+
+		let mut frame = Vec::new();
+		frame.write_message_with_version(Request::Statement(Statement::query("S")), ProtocolVersion::V3).unwrap();
+		assert_eq!(frame[0], 0x03);
+		assert_eq!(&frame[9..], &[0, 0, 0, 1, b'S', 0, 1, 0][..]);
+	}
+}
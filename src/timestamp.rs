@@ -0,0 +1,175 @@
+//! Client-side write timestamps.
+//!
+//! A connection with a generator sets the default timestamp of every QUERY,
+//! EXECUTE and BATCH that doesn't carry one already, so the order of writes
+//! is decided by the client instead of by whichever coordinator got them.
+
+use std::sync::Arc;
+use std::sync::atomic::{
+    AtomicI64,
+    Ordering
+};
+
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH
+};
+
+pub trait TimestampGenerator: Send + Sync {
+    /// Next write timestamp in microseconds since the epoch.
+    fn next_timestamp(&self) -> i64;
+}
+
+/// Hands out strictly increasing timestamps based on the system clock.
+///
+/// When the clock goes backwards, or several timestamps are asked for within
+/// the same microsecond, the last timestamp is incremented by one instead. The
+/// drift hook is called when the timestamps get ahead of the clock by more than
+/// the warning threshold, at most once per warning interval.
+pub struct MonotonicTimestampGenerator {
+    last: AtomicI64,
+    last_warning: AtomicI64,
+    warning_threshold: i64,
+    warning_interval: i64,
+    drift_hook: Option<Arc<DriftHook>>
+}
+
+/// Called with how many microseconds the timestamps are ahead of the clock.
+pub type DriftHook = dyn Fn(i64) + Send + Sync;
+
+/// A drift hook printing to stderr: `generator.set_drift_hook(Some(Arc::new(log_drift)))`.
+pub fn log_drift(drift: i64) {
+    eprintln!("Clock drift detected: timestamps are {}us ahead of the system clock", drift);
+}
+
+impl MonotonicTimestampGenerator {
+    /// Warns when more than a second ahead of the clock, at most once a second.
+    pub fn new() -> MonotonicTimestampGenerator {
+        MonotonicTimestampGenerator::with_warnings(1_000_000, 1_000_000)
+    }
+
+    /// Threshold and interval are in microseconds, a threshold of 0 turns warnings off.
+    pub fn with_warnings(warning_threshold: i64, warning_interval: i64) -> MonotonicTimestampGenerator {
+        MonotonicTimestampGenerator {
+            last: AtomicI64::new(i64::MIN),
+            last_warning: AtomicI64::new(i64::MIN),
+            warning_threshold: warning_threshold,
+            warning_interval: warning_interval,
+            drift_hook: None
+        }
+    }
+
+    /// Hands clock drift beyond the warning threshold to `hook`, e.g. to log it.
+    pub fn set_drift_hook(&mut self, hook: Option<Arc<DriftHook>>) {
+        self.drift_hook = hook;
+    }
+
+    fn next_after(&self, now: i64) -> i64 {
+        let mut last = self.last.load(Ordering::SeqCst);
+        loop {
+            let next = if now > last { now } else { last + 1 };
+            match self.last.compare_exchange_weak(last, next, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    self.check_drift(now, next - now);
+                    return next;
+                }
+                Err(actual) => last = actual
+            }
+        }
+    }
+
+    fn check_drift(&self, now: i64, drift: i64) {
+        let hook = match self.drift_hook {
+            Some(ref hook) => hook,
+            None => return
+        };
+        if self.warning_threshold <= 0 || drift <= self.warning_threshold {
+            return;
+        }
+        let last_warning = self.last_warning.load(Ordering::SeqCst);
+        if now.saturating_sub(last_warning) < self.warning_interval {
+            return;
+        }
+        // only the thread that moves last_warning forward reports
+        if self.last_warning.compare_exchange(last_warning, now, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+            hook(drift);
+        }
+    }
+}
+
+impl Default for MonotonicTimestampGenerator {
+    fn default() -> MonotonicTimestampGenerator {
+        MonotonicTimestampGenerator::new()
+    }
+}
+
+impl TimestampGenerator for MonotonicTimestampGenerator {
+    fn next_timestamp(&self) -> i64 {
+        self.next_after(now_micros())
+    }
+}
+
+fn now_micros() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as i64 * 1_000_000 + elapsed.subsec_micros() as i64,
+        Err(e) => -(e.duration().as_secs() as i64 * 1_000_000 + e.duration().subsec_micros() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_follows_clock() {
+        let generator = MonotonicTimestampGenerator::new();
+        assert_eq!(generator.next_after(100), 100);
+        assert_eq!(generator.next_after(250), 250);
+    }
+
+    #[test]
+    fn test_strictly_increasing() {
+        let generator = MonotonicTimestampGenerator::with_warnings(0, 0);
+        assert_eq!(generator.next_after(100), 100);
+        // same microsecond
+        assert_eq!(generator.next_after(100), 101);
+        // clock went backwards
+        assert_eq!(generator.next_after(50), 102);
+        assert_eq!(generator.next_after(103), 103);
+    }
+
+    #[test]
+    fn test_drift_hook() {
+        let reported = Arc::new(Mutex::new(vec!()));
+        let mut generator = MonotonicTimestampGenerator::with_warnings(10, 100);
+        {
+            let reported = reported.clone();
+            generator.set_drift_hook(Some(Arc::new(move |drift| reported.lock().unwrap().push(drift))));
+        }
+
+        generator.next_after(1000);
+        for _ in 0..20 {
+            generator.next_after(1000);
+        }
+        // once past the threshold, then not again within the interval
+        assert_eq!(*reported.lock().unwrap(), vec!(11));
+        for _ in 0..100 {
+            generator.next_after(1001);
+        }
+        assert_eq!(reported.lock().unwrap().len(), 1);
+        // last is 1120, the interval is up
+        generator.next_after(1110);
+        assert_eq!(*reported.lock().unwrap(), vec!(11, 11));
+    }
+
+    #[test]
+    fn test_system_clock() {
+        let generator = MonotonicTimestampGenerator::new();
+        let first = generator.next_timestamp();
+        let second = generator.next_timestamp();
+        assert!(first > 0);
+        assert!(second > first);
+    }
+}
@@ -0,0 +1,118 @@
+//! Query traces.
+//!
+//! A statement or batch sent with `tracing(true)` comes back with a tracing
+//! session id. The server writes what it did into `system_traces.sessions`
+//! and `system_traces.events` asynchronously, `Connection::trace` reads it back.
+
+use std::io::{
+    Result,
+    Error,
+    ErrorKind
+};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use connection::Connection;
+
+use shared::{
+    Consistency,
+    Row
+};
+
+use statement::Statement;
+
+use paging::into_page;
+
+static SESSION_QUERY: &'static str =
+    "SELECT client, command, coordinator, duration, parameters, request, started_at \
+     FROM system_traces.sessions WHERE session_id = ?";
+
+static EVENTS_QUERY: &'static str =
+    "SELECT event_id, activity, source, source_elapsed, thread \
+     FROM system_traces.events WHERE session_id = ?";
+
+/// How often, and how long apart, the session is looked up before giving up.
+static ATTEMPTS: u32 = 5;
+static RETRY_DELAY_MS: u64 = 200;
+
+#[derive(Debug, Clone)]
+pub struct Trace {
+    pub session_id: Uuid,
+    pub client: Option<IpAddr>,
+    pub command: Option<String>,
+    pub coordinator: Option<IpAddr>,
+    /// Microseconds the coordinator spent on the request.
+    pub duration: i32,
+    pub parameters: HashMap<String, String>,
+    pub request: Option<String>,
+    /// Milliseconds since the epoch.
+    pub started_at: Option<i64>,
+    pub events: Vec<TraceEvent>
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceEvent {
+    pub event_id: String,
+    pub activity: Option<String>,
+    pub source: Option<IpAddr>,
+    /// Microseconds since the request reached `source`.
+    pub source_elapsed: Option<i32>,
+    pub thread: Option<String>
+}
+
+/// Loads a trace, waiting for the server to finish writing it.
+pub fn load_trace(conn: &mut Connection, session_id: Uuid) -> Result<Trace> {
+    for attempt in 0..ATTEMPTS {
+        if attempt > 0 {
+            thread::sleep(Duration::from_millis(RETRY_DELAY_MS));
+        }
+        let statement = Statement::query(SESSION_QUERY).values((session_id,)).consistency(Consistency::One);
+        let (rows, _) = try!(conn.execute(statement).and_then(into_page));
+        // the duration is written last, without it the session is still in progress
+        let session = match rows.into_iter().next() {
+            Some(row) => row,
+            None => continue
+        };
+        let duration: Option<i32> = try!(session.get_by_name_as("duration"));
+        let duration = match duration {
+            Some(duration) => duration,
+            None => continue
+        };
+
+        let statement = Statement::query(EVENTS_QUERY).values((session_id,)).consistency(Consistency::One);
+        let (rows, _) = try!(conn.execute(statement).and_then(into_page));
+        let mut events = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            events.push(try!(read_event(row)));
+        }
+
+        let parameters: Option<HashMap<String, String>> = try!(session.get_by_name_as("parameters"));
+        return Ok(Trace {
+            session_id: session_id,
+            client: try!(session.get_by_name_as("client")),
+            command: try!(session.get_by_name_as("command")),
+            coordinator: try!(session.get_by_name_as("coordinator")),
+            duration: duration,
+            parameters: parameters.unwrap_or_default(),
+            request: try!(session.get_by_name_as("request")),
+            started_at: try!(session.get_by_name_as("started_at")),
+            events: events
+        });
+    }
+    Err(Error::new(ErrorKind::TimedOut, format!("Trace {} is not available yet", session_id.hyphenated())))
+}
+
+fn read_event(row: &Row) -> Result<TraceEvent> {
+    Ok(TraceEvent {
+        event_id: try!(row.get_by_name_as("event_id")),
+        activity: try!(row.get_by_name_as("activity")),
+        source: try!(row.get_by_name_as("source")),
+        source_elapsed: try!(row.get_by_name_as("source_elapsed")),
+        thread: try!(row.get_by_name_as("thread"))
+    })
+}
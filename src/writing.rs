@@ -1,291 +1,594 @@
 use std::io::{
 	Write,
-	Result
+	Result,
+	Error,
+	ErrorKind
 };
 
 use std::mem::size_of;
+use std::collections::HashMap;
 
-use byteorder::{WriteBytesExt, BigEndian};
+use byteorder::{WriteBytesExt, ByteOrder, BigEndian, LittleEndian};
+
+#[cfg(feature = "lz4")]
+use lz4_flex::block::compress;
+
+use checksum::{
+	crc24,
+	crc32
+};
 
 use shared::{
-	CQL_BINARY_PROTOCOL_VERSION,
+	ProtocolVersion,
+	Compression,
 	Request,
-    FrameFlag,
 	QueryFlag,
-	BatchType,
 	BatchFlag,
-	BatchQuery,
 	BatchQueryKind,
 	Column
 };
 
+use statement::{
+	Statement,
+	Batch,
+	Target,
+	Values
+};
+
 
 pub trait WriteMessage {
 	fn write_message(&mut self, Request) -> Result<()>;
+	/// Like `write_message`, encoding for the negotiated protocol version.
+	fn write_message_with_version(&mut self, Request, ProtocolVersion) -> Result<()>;
 }
 
 impl<W: Write> WriteMessage for W {
 fn write_message(&mut self, message: Request) -> Result<()> {
-	let mut header = Vec::new();
-
-	try!(WriteBytesExt::write_u8(&mut header, CQL_BINARY_PROTOCOL_VERSION));
-	try!(WriteBytesExt::write_u8(&mut header, FrameFlag::None as u8));
-	try!(WriteBytesExt::write_u16::<BigEndian>(&mut header, 1));
-	try!(WriteBytesExt::write_u8(&mut header, message.opcode()));
-
-
-	let mut buf = Vec::new();
+	self.write_message_with_version(message, ProtocolVersion::V4)
+}
 
-	match message {
-			Request::Startup(ref hash_map) => {
-				try!(buf.write_u16::<BigEndian>(hash_map.len() as u16));
-				for (key, val) in hash_map.iter() {
-					try!(buf.write_u16::<BigEndian>(key.len() as u16));
-					try!(Write::write(&mut buf, key.as_bytes()));
-					try!(buf.write_u16::<BigEndian>(val.len() as u16));
-					try!(Write::write(&mut buf, val.as_bytes()));
-				}
-			}
-			Request::Query(ref query, ref consistency) => {
-				try!(buf.write_i32::<BigEndian>(query.len() as i32));
-				try!(Write::write(&mut buf, query.as_bytes()));
-				try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
-				try!(WriteBytesExt::write_u8(&mut buf, QueryFlag::None as u8));
-			}
-			Request::PrmQuery(ref query, ref values, ref consistency) => {
-				//println!("query is {}", query);
-				try!(buf.write_i32::<BigEndian>(query.len() as i32));
-				try!(Write::write(&mut buf, query.as_bytes()));
-				try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
+fn write_message_with_version(&mut self, message: Request, version: ProtocolVersion) -> Result<()> {
+	let mut encoder = FrameEncoder::new();
+	let frame = try!(encoder.encode(&message, version, 1));
+	self.write_all(frame)
+    }
+}
 
-				try!(WriteBytesExt::write_u8(&mut buf, QueryFlag::Values as u8));
+/// Length of a frame header, the body length included.
+const HEADER_LEN: usize = 9;
 
+/// Encodes frames into a buffer that is kept between requests, so sending
+/// doesn't allocate once the buffer has grown to fit.
+pub struct FrameEncoder {
+	buf: Vec<u8>
+}
 
-				try!(buf.write_u16::<BigEndian>(values.len() as u16));
+impl FrameEncoder {
+	pub fn new() -> FrameEncoder {
+		FrameEncoder {
+			buf: Vec::new()
+		}
+	}
 
-				write_values(&mut buf, values);
-			}
-            Request::PrmQueryWithNames(ref query, ref named_values, ref consistency) => {
-                //println!("query is {}", query);
-                try!(buf.write_i32::<BigEndian>(query.len() as i32));
-                try!(Write::write(&mut buf, query.as_bytes()));
-                try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
+	/// Encodes a whole frame on `stream`, header included. The body length is
+	/// patched in once the body is written.
+	pub fn encode(&mut self, message: &Request, version: ProtocolVersion, stream: i16) -> Result<&[u8]> {
+		if version < ProtocolVersion::V4 && message.custom_payload().is_some() {
+			return Err(Error::new(ErrorKind::InvalidInput, "Custom payloads need protocol v4 or later"));
+		}
 
-                try!(WriteBytesExt::write_u8(&mut buf, QueryFlag::Values as u8 | QueryFlag::WithNamesForValues as u8));
+		let buf = &mut self.buf;
+		buf.clear();
 
+		try!(buf.write_u8(version as u8));
+		try!(buf.write_u8(message.flags()));
+		try!(buf.write_i16::<BigEndian>(stream));
+		try!(buf.write_u8(message.opcode()));
+		try!(buf.write_u32::<BigEndian>(0));
 
-                try!(buf.write_u16::<BigEndian>(named_values.len() as u16));
+		try!(write_body(buf, message, version));
 
-                write_named_values(&mut buf, named_values);
-            }
-			Request::Prepare(ref query) => {
-				try!(buf.write_i32::<BigEndian>(query.len() as i32));
-				try!(Write::write(&mut buf, query.as_bytes()));
-			}
-			Request::Execute(ref id, ref values, ref consistency) => {
-				try!(buf.write_u16::<BigEndian>(id.len() as u16));
-				try!(Write::write(&mut buf, id));
-				try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
+		let body_len = (buf.len() - HEADER_LEN) as u32;
+		BigEndian::write_u32(&mut buf[HEADER_LEN - 4..HEADER_LEN], body_len);
 
-				try!(WriteBytesExt::write_u8(&mut buf, QueryFlag::Values as u8));
+		Ok(&buf[..])
+	}
+}
 
+impl Default for FrameEncoder {
+	fn default() -> FrameEncoder {
+		FrameEncoder::new()
+	}
+}
 
-				try!(buf.write_u16::<BigEndian>(values.len() as u16));
+fn write_body(buf: &mut Vec<u8>, message: &Request, version: ProtocolVersion) -> Result<()> {
+	if let Some(custom_payload) = message.custom_payload() {
+		try!(write_bytes_map(buf, custom_payload));
+	}
 
-				write_values(&mut buf, values);
+	match *message {
+		Request::Startup(ref hash_map) => {
+			try!(buf.write_u16::<BigEndian>(hash_map.len() as u16));
+			for (key, val) in hash_map.iter() {
+				try!(buf.write_u16::<BigEndian>(key.len() as u16));
+				try!(Write::write_all(buf, key.as_bytes()));
+				try!(buf.write_u16::<BigEndian>(val.len() as u16));
+				try!(Write::write_all(buf, val.as_bytes()));
 			}
-			Request::Batch(ref queries, ref consistency) => {
-				try!(WriteBytesExt::write_u8(&mut buf, BatchType::Logged as u8));
-				try!(buf.write_u16::<BigEndian>(queries.len() as u16));
-				for query in queries.iter() {
-					match query {
-						&BatchQuery::Simple(ref query) => {
-							try!(WriteBytesExt::write_u8(&mut buf, BatchQueryKind::Simple as u8));
-							try!(buf.write_i32::<BigEndian>(query.len() as i32));
-							try!(Write::write(&mut buf, query.as_bytes()));
-							try!(buf.write_u16::<BigEndian>(0 as u16));
-						}
-						&BatchQuery::SimpleWithParams(ref query, ref values) => {
-							//println!("query is {}", query);
-							try!(WriteBytesExt::write_u8(&mut buf, BatchQueryKind::Simple as u8));
-							try!(buf.write_i32::<BigEndian>(query.len() as i32));
-							try!(Write::write(&mut buf, query.as_bytes()));
-
-							try!(buf.write_u16::<BigEndian>(values.len() as u16));
-
-							write_values(&mut buf, values);
-						}
-						&BatchQuery::Prepared(ref id, ref values) => {
-							try!(WriteBytesExt::write_u8(&mut buf, BatchQueryKind::Prepared as u8));
-							try!(buf.write_u16::<BigEndian>(id.len() as u16));
-							try!(Write::write(&mut buf, id));
-
-							try!(buf.write_u16::<BigEndian>(values.len() as u16));
-
-							write_values(&mut buf, values);
-						}
-					}
+		}
+		Request::Prepare(ref query) => {
+			try!(buf.write_i32::<BigEndian>(query.len() as i32));
+			try!(Write::write_all(buf, query.as_bytes()));
+			if version >= ProtocolVersion::V5 {
+				try!(buf.write_u32::<BigEndian>(0)); // no keyspace
+			}
+		}
+		Request::Statement(ref statement) => {
+			try!(write_target(buf, &statement.target));
+			if let Target::Prepared(_) = statement.target {
+				if version >= ProtocolVersion::V5 {
+					let id = statement.result_metadata_id.as_ref().map(|id| &id[..]).unwrap_or(&[]);
+					try!(buf.write_u16::<BigEndian>(id.len() as u16));
+					try!(Write::write_all(buf, id));
 				}
-				try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
-				try!(WriteBytesExt::write_u8(&mut buf, BatchFlag::None as u8));
 			}
+			try!(write_query_parameters(buf, statement, version));
+		}
+		Request::Batch(ref batch) => {
+			try!(write_batch(buf, batch, version));
+		}
 
-            Request::PagedQuery(ref query, ref consistency, ref result_page_size, ref paging_state) => {
-
-                let mut flags = QueryFlag::PageSize as u8;
-
-                if paging_state.is_some() {
-                    flags = flags | QueryFlag::WithPagingState as u8;
-                }
-
-                try!(buf.write_i32::<BigEndian>(query.len() as i32));
-                try!(Write::write(&mut buf, query.as_bytes()));
-                try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
-                try!(WriteBytesExt::write_u8(&mut buf, flags));
-
-                try!(buf.write_i32::<BigEndian>(*result_page_size));
-
-                if let &Some(ref bytes) = paging_state {
-                    try!(buf.write_i32::<BigEndian>(bytes.len() as i32));
-                    try!(Write::write(&mut buf, bytes));
-                }
-            }
-            Request::PagedPrmQuery(ref query, ref values, ref consistency, ref result_page_size, ref paging_state) => {
-
-                let mut flags = QueryFlag::Values as u8 | QueryFlag::PageSize as u8;
-
-                if paging_state.is_some() {
-                    flags = flags | QueryFlag::WithPagingState as u8;
-                }
-
-                try!(buf.write_i32::<BigEndian>(query.len() as i32));
-                try!(Write::write(&mut buf, query.as_bytes()));
-                try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
-
-                try!(WriteBytesExt::write_u8(&mut buf, flags));
-
-
-                try!(buf.write_u16::<BigEndian>(values.len() as u16));
-
-                write_values(&mut buf, values);
-
-                try!(buf.write_i32::<BigEndian>(*result_page_size));
-
-                if let &Some(ref bytes) = paging_state {
-                    try!(buf.write_i32::<BigEndian>(bytes.len() as i32));
-                    try!(Write::write(&mut buf, bytes));
-                }
-            }
-            Request::PagedPrmQueryWithNames(ref query, ref named_values, ref consistency, ref result_page_size, ref paging_state) => {
-
-                let mut flags = QueryFlag::Values as u8 | QueryFlag::WithNamesForValues as u8 | QueryFlag::PageSize as u8;
-
-                if paging_state.is_some() {
-                    flags = flags | QueryFlag::WithPagingState as u8;
-                }
+		_ => ()
+	}
 
-                try!(buf.write_i32::<BigEndian>(query.len() as i32));
-                try!(Write::write(&mut buf, query.as_bytes()));
-                try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
+	Ok(())
+}
 
-                try!(WriteBytesExt::write_u8(&mut buf, flags));
+/// Largest payload of a v5 segment, before compression.
+static MAX_SEGMENT_PAYLOAD: usize = 0x1FFFF;
+
+/// Wraps an encoded frame in v5 segments. A frame that doesn't fit in one
+/// segment is split over several that aren't self-contained.
+pub fn write_segments<W: Write>(out: &mut W, frame: &[u8], compression: Compression) -> Result<()> {
+	let self_contained = frame.len() <= MAX_SEGMENT_PAYLOAD;
+	for chunk in frame.chunks(MAX_SEGMENT_PAYLOAD) {
+		let mut header = [0u8; 8];
+		match compression {
+			Compression::None => {
+				let fields = chunk.len() as u64 | (self_contained as u64) << 17;
+				try!(write_segment(out, &mut header, 3, fields, chunk));
+			}
+			#[cfg(feature = "lz4")]
+			Compression::Lz4 => {
+				let compressed = compress(chunk);
+				// not worth it, send as is with an uncompressed length of 0
+				let (payload, uncompressed_len) = if compressed.len() < chunk.len() {
+					(&compressed[..], chunk.len())
+				} else {
+					(chunk, 0)
+				};
+				let fields = payload.len() as u64 | (uncompressed_len as u64) << 17 | (self_contained as u64) << 34;
+				try!(write_segment(out, &mut header, 5, fields, payload));
+			}
+		}
+	}
+	Ok(())
+}
 
+fn write_segment<W: Write>(out: &mut W, header: &mut [u8; 8], header_len: usize, fields: u64, payload: &[u8]) -> Result<()> {
+	LittleEndian::write_uint(&mut header[..header_len], fields, header_len);
+	let header_crc = crc24(&header[..header_len]);
+	LittleEndian::write_uint(&mut header[header_len..header_len + 3], header_crc as u64, 3);
 
-                try!(buf.write_u16::<BigEndian>(named_values.len() as u16));
+	try!(out.write_all(&header[..header_len + 3]));
+	try!(out.write_all(payload));
+	out.write_u32::<LittleEndian>(crc32(payload))
+}
 
-                write_named_values(&mut buf, named_values);
+fn write_bytes_map(buf: &mut Vec<u8>, map: &HashMap<String, Vec<u8>>) -> Result<()> {
+	try!(buf.write_u16::<BigEndian>(map.len() as u16));
+	for (key, value) in map.iter() {
+		try!(buf.write_u16::<BigEndian>(key.len() as u16));
+		try!(Write::write_all(buf, key.as_bytes()));
+		try!(buf.write_i32::<BigEndian>(value.len() as i32));
+		try!(Write::write_all(buf, value));
+	}
+	Ok(())
+}
 
-                try!(buf.write_i32::<BigEndian>(*result_page_size));
+fn write_target(buf: &mut Vec<u8>, target: &Target) -> Result<()> {
+	match *target {
+		Target::Query(ref query) => {
+			try!(buf.write_i32::<BigEndian>(query.len() as i32));
+			try!(Write::write_all(buf, query.as_bytes()));
+		}
+		Target::Prepared(ref id) => {
+			try!(buf.write_u16::<BigEndian>(id.len() as u16));
+			try!(Write::write_all(buf, id));
+		}
+	}
+	Ok(())
+}
 
-                if let &Some(ref bytes) = paging_state {
-                    try!(buf.write_i32::<BigEndian>(bytes.len() as i32));
-                    try!(Write::write(&mut buf, bytes));
-                }
-            }
-            Request::PagedExecute(ref id, ref values, ref consistency, ref result_page_size, ref paging_state) => {
+/// The flags of QUERY, EXECUTE and BATCH grew from a byte to an int in v5.
+fn write_flags(buf: &mut Vec<u8>, flags: u32, version: ProtocolVersion) -> Result<()> {
+	if version >= ProtocolVersion::V5 {
+		try!(buf.write_u32::<BigEndian>(flags));
+	} else {
+		try!(WriteBytesExt::write_u8(buf, flags as u8));
+	}
+	Ok(())
+}
 
-                let mut flags = QueryFlag::Values as u8  | QueryFlag::PageSize as u8;
+fn check_v5_options(keyspace: &Option<String>, now_in_seconds: &Option<i32>, version: ProtocolVersion) -> Result<()> {
+	if version < ProtocolVersion::V5 && (keyspace.is_some() || now_in_seconds.is_some()) {
+		return Err(Error::new(ErrorKind::InvalidInput, "Per-request keyspace and now_in_seconds need protocol v5"));
+	}
+	Ok(())
+}
 
-                if paging_state.is_some() {
-                    flags = flags | QueryFlag::WithPagingState as u8;
-                }
+fn write_v5_options(buf: &mut Vec<u8>, keyspace: &Option<String>, now_in_seconds: &Option<i32>) -> Result<()> {
+	if let Some(ref keyspace) = *keyspace {
+		try!(buf.write_u16::<BigEndian>(keyspace.len() as u16));
+		try!(Write::write_all(buf, keyspace.as_bytes()));
+	}
+	if let Some(now_in_seconds) = *now_in_seconds {
+		try!(buf.write_i32::<BigEndian>(now_in_seconds));
+	}
+	Ok(())
+}
 
-                try!(buf.write_u16::<BigEndian>(id.len() as u16));
-                try!(Write::write(&mut buf, id));
-                try!(buf.write_u16::<BigEndian>((*consistency).clone() as u16));
+fn write_batch(buf: &mut Vec<u8>, batch: &Batch, version: ProtocolVersion) -> Result<()> {
+	try!(check_v5_options(&batch.keyspace, &batch.now_in_seconds, version));
+
+	let with_names = batch.with_names();
+
+	try!(WriteBytesExt::write_u8(buf, batch.batch_type as u8));
+	try!(buf.write_u16::<BigEndian>(batch.statements.len() as u16));
+	for &(ref target, ref values) in batch.statements.iter() {
+		let kind = match *target {
+			Target::Query(_) => BatchQueryKind::Simple,
+			Target::Prepared(_) => BatchQueryKind::Prepared
+		};
+		try!(WriteBytesExt::write_u8(buf, kind as u8));
+		try!(write_target(buf, target));
+		try!(check_unset(values, version));
+		match *values {
+			Values::None => {
+				try!(buf.write_u16::<BigEndian>(0));
+			}
+			Values::Positional(ref values) if !with_names || values.is_empty() => {
+				try!(buf.write_u16::<BigEndian>(values.len() as u16));
+				try!(write_values(buf, values));
+			}
+			Values::Named(ref named_values) => {
+				try!(buf.write_u16::<BigEndian>(named_values.len() as u16));
+				try!(write_named_values(buf, named_values));
+			}
+			Values::Positional(_) =>
+				return Err(Error::new(ErrorKind::InvalidInput, "A batch can't mix named and positional values"))
+		}
+	}
 
-                try!(WriteBytesExt::write_u8(&mut buf, flags));
+	let mut flags = BatchFlag::None as u32;
+	if batch.serial_consistency.is_some() {
+		flags = flags | BatchFlag::WithSerialConsistency as u32;
+	}
+	if batch.default_timestamp.is_some() {
+		flags = flags | BatchFlag::WithDefaultTimestamp as u32;
+	}
+	if with_names {
+		flags = flags | BatchFlag::WithNamesForValues as u32;
+	}
+	if batch.keyspace.is_some() {
+		flags = flags | BatchFlag::WithKeyspace as u32;
+	}
+	if batch.now_in_seconds.is_some() {
+		flags = flags | BatchFlag::WithNowInSeconds as u32;
+	}
+	try!(buf.write_u16::<BigEndian>(batch.consistency as u16));
+	try!(write_flags(buf, flags, version));
+	if let Some(serial_consistency) = batch.serial_consistency {
+		try!(buf.write_u16::<BigEndian>(serial_consistency as u16));
+	}
+	if let Some(timestamp) = batch.default_timestamp {
+		try!(buf.write_i64::<BigEndian>(timestamp));
+	}
+	write_v5_options(buf, &batch.keyspace, &batch.now_in_seconds)
+}
 
+fn write_query_parameters(buf: &mut Vec<u8>, statement: &Statement, version: ProtocolVersion) -> Result<()> {
+	try!(check_v5_options(&statement.keyspace, &statement.now_in_seconds, version));
+	try!(check_unset(&statement.values, version));
 
-                try!(buf.write_u16::<BigEndian>(values.len() as u16));
+	let mut flags = QueryFlag::None as u32;
 
-                write_values(&mut buf, values);
+	match statement.values {
+		Values::Positional(_) => flags = flags | QueryFlag::Values as u32,
+		Values::Named(_) => flags = flags | QueryFlag::Values as u32 | QueryFlag::WithNamesForValues as u32,
+		Values::None => {}
+	}
+	if statement.skip_metadata {
+		flags = flags | QueryFlag::SkipMetadata as u32;
+	}
+	if statement.page_size.is_some() {
+		flags = flags | QueryFlag::PageSize as u32;
+	}
+	if statement.paging_state.is_some() {
+		flags = flags | QueryFlag::WithPagingState as u32;
+	}
+	if statement.serial_consistency.is_some() {
+		flags = flags | QueryFlag::WithSerialConsistency as u32;
+	}
+	if statement.default_timestamp.is_some() {
+		flags = flags | QueryFlag::WithDefaultTimestamp as u32;
+	}
+	if statement.keyspace.is_some() {
+		flags = flags | QueryFlag::WithKeyspace as u32;
+	}
+	if statement.now_in_seconds.is_some() {
+		flags = flags | QueryFlag::WithNowInSeconds as u32;
+	}
 
-                try!(buf.write_i32::<BigEndian>(*result_page_size));
+	try!(buf.write_u16::<BigEndian>(statement.consistency as u16));
+	try!(write_flags(buf, flags, version));
 
-                if let &Some(ref bytes) = paging_state {
-                    try!(buf.write_i32::<BigEndian>(bytes.len() as i32));
-                    try!(Write::write(&mut buf, bytes));
-                }
-            }
-			_ => ()
+	match statement.values {
+		Values::Positional(ref values) => {
+			try!(buf.write_u16::<BigEndian>(values.len() as u16));
+			try!(write_values(buf, values));
 		}
-
-	try!(self.write(header.as_slice()));
-	try!(self.write_u32::<BigEndian>(buf.len() as u32));
-	try!(self.write(buf.as_slice()));
-
-	Ok(())
-    }
+		Values::Named(ref named_values) => {
+			try!(buf.write_u16::<BigEndian>(named_values.len() as u16));
+			try!(write_named_values(buf, named_values));
+		}
+		Values::None => {}
+	}
+	if let Some(page_size) = statement.page_size {
+		try!(buf.write_i32::<BigEndian>(page_size));
+	}
+	if let Some(ref bytes) = statement.paging_state {
+		try!(buf.write_i32::<BigEndian>(bytes.len() as i32));
+		try!(Write::write_all(buf, bytes));
+	}
+	if let Some(serial_consistency) = statement.serial_consistency {
+		try!(buf.write_u16::<BigEndian>(serial_consistency as u16));
+	}
+	if let Some(timestamp) = statement.default_timestamp {
+		try!(buf.write_i64::<BigEndian>(timestamp));
+	}
+	write_v5_options(buf, &statement.keyspace, &statement.now_in_seconds)
 }
 
-fn write_values(buf: &mut Vec<u8>, values: &Vec<Column>) -> Result<()> {
+pub(crate) fn write_values(buf: &mut Vec<u8>, values: &Vec<Column>) -> Result<()> {
 	for col in values.iter() {
-		try!(buf.write_i32::<BigEndian>(value_size(col) as i32));
-		write_value(buf, col);
+		try!(write_sized_value(buf, col));
 	}
 	Ok(())
 }
 
-fn write_named_values(buf: &mut Vec<u8>, named_values: &Vec<(String, Column)>) -> Result<()> {
+pub(crate) fn write_named_values(buf: &mut Vec<u8>, named_values: &Vec<(String, Column)>) -> Result<()> {
     for &(ref name, ref col) in named_values.iter() {
         try!(buf.write_u16::<BigEndian>(name.len() as u16));
-        try!(Write::write(buf, name.as_bytes()));
-        try!(buf.write_i32::<BigEndian>(value_size(col) as i32));
-        write_value(buf, col);
+        try!(Write::write_all(buf, name.as_bytes()));
+        try!(write_sized_value(buf, col));
     }
     Ok(())
 }
 
-fn value_size(value: &Column) -> usize {
-	match value {
-		&Column::String(ref v) => v.len(),
-		&Column::Int(ref v) => size_of::<i32>(),
-		&Column::Bigint(ref v) => size_of::<i64>(),
-		&Column::Float(ref v) => size_of::<f32>(),
-		&Column::Double(ref v) => size_of::<f64>(),
-		&Column::Timestamp(ref v) => size_of::<i64>(),
-		&Column::Set(ref v) | &Column::List(ref v) => size_of::<i32>() + ((*v).len()) * (value_size(&(*v)[0]) + size_of::<i32>()),
-		_ => 0
+/// Writes a [value]: its length followed by its bytes, -1 for null or -2 for unset.
+fn write_sized_value(buf: &mut Vec<u8>, value: &Column) -> Result<()> {
+	match *value {
+		Column::None | Column::Null => {try!(buf.write_i32::<BigEndian>(-1));}
+		Column::Unset => {try!(buf.write_i32::<BigEndian>(-2));}
+		_ => {
+			// the length is patched in afterwards, so it is always what was actually written
+			let start = buf.len();
+			try!(buf.write_i32::<BigEndian>(0));
+			try!(write_value(buf, value));
+			let len = buf.len() - start - size_of::<i32>();
+			BigEndian::write_i32(&mut buf[start..start + size_of::<i32>()], len as i32);
+		}
 	}
+	Ok(())
+}
+
+/// Unset values came with protocol v4.
+fn check_unset(values: &Values, version: ProtocolVersion) -> Result<()> {
+	let unset = match *values {
+		Values::Positional(ref values) => values.iter().any(|value| *value == Column::Unset),
+		Values::Named(ref named_values) => named_values.iter().any(|&(_, ref value)| *value == Column::Unset),
+		Values::None => false
+	};
+	if unset && version < ProtocolVersion::V4 {
+		return Err(Error::new(ErrorKind::InvalidInput, "Unset values need protocol v4 or later"));
+	}
+	Ok(())
 }
 
 fn write_value(buf: &mut Vec<u8>, value: &Column) -> Result<()> {
 	match value {
-		&Column::String(ref v) => {try!(Write::write(buf, v.as_bytes()));}
+		&Column::String(ref v) => {try!(Write::write_all(buf, v.as_bytes()));}
+		&Column::Boolean(ref v) => {try!(buf.write_u8(*v as u8));}
+		&Column::Blob(ref v) => {try!(Write::write_all(buf, v));}
+		&Column::Tinyint(ref v) => {try!(buf.write_i8(*v));}
+		&Column::Smallint(ref v) => {try!(buf.write_i16::<BigEndian>(*v));}
 		&Column::Int(ref v) => {try!(buf.write_i32::<BigEndian>(*v));}
 		&Column::Bigint(ref v) => {try!(buf.write_i64::<BigEndian>(*v));}
 		&Column::Float(ref v) => {try!(buf.write_f32::<BigEndian>(*v));}
 		&Column::Double(ref v) => {try!(buf.write_f64::<BigEndian>(*v));}
 		&Column::Timestamp(ref v) => {try!(buf.write_i64::<BigEndian>(*v));}
 		&Column::Set(ref v) | &Column::List(ref v) => {
-			try!(buf.write_i32::<BigEndian>((*v).len() as i32));
-			for value in (*v).iter() {
-				try!(buf.write_i32::<BigEndian>(value_size(value) as i32));
-				write_value(buf, value);
+			try!(buf.write_i32::<BigEndian>(v.len() as i32));
+			for value in v.iter() {
+				try!(write_sized_value(buf, value));
+			}
+		},
+		&Column::Map(ref v) => {
+			try!(buf.write_i32::<BigEndian>(v.len() as i32));
+			for &(ref key, ref value) in v.iter() {
+				try!(write_sized_value(buf, key));
+				try!(write_sized_value(buf, value));
+			}
+		},
+		&Column::Tuple(ref v) => {
+			for value in v.iter() {
+				try!(write_sized_value(buf, value));
+			}
+		},
+		// fields are written in the order they are given, which must match the type definition
+		&Column::Udt(ref v) => {
+			for &(_, ref value) in v.iter() {
+				try!(write_sized_value(buf, value));
 			}
 		},
 		_ => {}
 	}
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+	use shared::{
+		Consistency,
+		Request,
+		Column,
+		ProtocolVersion
+	};
+
+	use statement::{
+		Statement,
+		Batch
+	};
+
+	use super::{
+		WriteMessage,
+		FrameEncoder
+	};
+
+	#[test]
+	fn test_batch_parameters() {
+		let batch = Batch::logged()
+			.query("U")
+			.consistency(Consistency::Quorum)
+			.serial_consistency(Consistency::Serial)
+			.default_timestamp(42);
+
+		let mut frame = Vec::new();
+		frame.write_message(Request::Batch(batch)).unwrap();
+
+		let body = &frame[9..];
+		assert_eq!(body, &[
+			0, 0, 1,                       // logged, one query
+			0, 0, 0, 0, 1, b'U', 0, 0,     // simple query without values
+			0, 4,                          // consistency
+			0x10 | 0x20,
+			0, 8,                          // serial consistency
+			0, 0, 0, 0, 0, 0, 0, 42        // timestamp
+		][..]);
+	}
+
+	#[test]
+	fn test_batch_statements() {
+		let batch = Batch::unlogged()
+			.query_with_names("U", &[("a", 1)][..])
+			.prepared(vec!(9), ());
+
+		let mut frame = Vec::new();
+		frame.write_message(Request::Batch(batch)).unwrap();
+
+		let body = &frame[9..];
+		assert_eq!(body, &[
+			1, 0, 2,                       // unlogged, two queries
+			0, 0, 0, 0, 1, b'U',           // simple query
+			0, 1, 0, 1, b'a', 0, 0, 0, 4, 0, 0, 0, 1,
+			1, 0, 1, 9, 0, 0,              // prepared statement without values
+			0, 1,                          // consistency
+			0x40
+		][..]);
+
+		let mixed = Batch::logged()
+			.query_with_names("U", &[("a", 1)][..])
+			.query_with("U", (1,));
+		assert!(Vec::new().write_message(Request::Batch(mixed)).is_err());
+	}
+
+	#[test]
+	fn test_null_and_unset() {
+		let statement = Statement::query("U").values((Column::Null, Column::Unset, Column::None, Some(1), None::<i32>));
+
+		let mut frame = Vec::new();
+		frame.write_message(Request::Statement(statement.clone())).unwrap();
+
+		assert_eq!(&frame[9..], &[
+			0, 0, 0, 1, b'U',
+			0, 1, 0x01,
+			0, 5,
+			0xFF, 0xFF, 0xFF, 0xFF,
+			0xFF, 0xFF, 0xFF, 0xFE,
+			0xFF, 0xFF, 0xFF, 0xFF,
+			0, 0, 0, 4, 0, 0, 0, 1,
+			0xFF, 0xFF, 0xFF, 0xFF
+		][..]);
+
+		assert!(Vec::new().write_message_with_version(Request::Statement(statement), ProtocolVersion::V3).is_err());
+	}
+
+	fn encode(value: Column) -> Vec<u8> {
+		let mut frame = Vec::new();
+		frame.write_message(Request::Statement(Statement::query("U").values(vec!(value)))).unwrap();
+		frame[19..].to_vec()
+	}
+
+	#[test]
+	fn test_small_integers() {
+		assert_eq!(encode(Column::Tinyint(-1)), vec![0, 0, 0, 1, 0xFF]);
+		assert_eq!(encode(Column::Smallint(258)), vec![0, 0, 0, 2, 1, 2]);
+	}
+
+	#[test]
+	fn test_collections() {
+		assert_eq!(encode(Column::List(vec!())), vec![0, 0, 0, 4, 0, 0, 0, 0]);
+
+		let list = Column::List(vec!(Column::String("a".to_string()), Column::String("bcd".to_string())));
+		assert_eq!(encode(list), vec![
+			0, 0, 0, 16, 0, 0, 0, 2,
+			0, 0, 0, 1, b'a',
+			0, 0, 0, 3, b'b', b'c', b'd'
+		]);
+
+		let map = Column::Map(vec!((Column::String("k".to_string()), Column::Int(1))));
+		assert_eq!(encode(map), vec![
+			0, 0, 0, 17, 0, 0, 0, 1,
+			0, 0, 0, 1, b'k',
+			0, 0, 0, 4, 0, 0, 0, 1
+		]);
+
+		let nested = Column::List(vec!(Column::Set(vec!(Column::Int(1))), Column::Set(vec!())));
+		assert_eq!(encode(nested), vec![
+			0, 0, 0, 28, 0, 0, 0, 2,
+			0, 0, 0, 12, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 1,
+			0, 0, 0, 4, 0, 0, 0, 0
+		]);
+	}
+
+	#[test]
+	fn test_frame_encoder() {
+		let mut encoder = FrameEncoder::new();
+
+		let long = Statement::query("SELECT * FROM a_table_with_a_long_name");
+		let short = Statement::query("SELECT 1");
+
+		let mut expected = Vec::new();
+		expected.write_message(Request::Statement(short.clone())).unwrap();
+
+		// a shorter frame after a longer one leaves nothing of the first behind
+		encoder.encode(&Request::Statement(long), ProtocolVersion::V4, 7).unwrap();
+		let frame = encoder.encode(&Request::Statement(short), ProtocolVersion::V4, 1).unwrap();
+		assert_eq!(frame, &expected[..]);
+		assert_eq!(&frame[5..9], &[0, 0, 0, (frame.len() - 9) as u8]);
+
+		let frame = encoder.encode(&Request::Options, ProtocolVersion::V4, 300).unwrap();
+		assert_eq!(&frame[2..4], &[1, 44]);
+	}
+}
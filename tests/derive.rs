@@ -0,0 +1,92 @@
+#![cfg(feature = "derive")]
+
+extern crate rustcql;
+
+use std::sync::Arc;
+
+use rustcql::{
+	FromCql,
+	FromRow,
+	IntoValues,
+	IntoNamedValues,
+	MappingError,
+	ToCql,
+	Udt
+};
+
+use rustcql::shared::{
+	Column,
+	ColumnSpec,
+	ColumnType,
+	CollectionSpec,
+	Row
+};
+
+#[derive(Debug, PartialEq, FromRow, IntoValues)]
+struct User {
+	#[cql(rename = "user_id")]
+	id: String,
+	age: i32,
+	height: Option<f32>,
+	#[cql(default)]
+	tags: Vec<String>
+}
+
+#[derive(Debug, PartialEq, Udt)]
+struct Address {
+	street: String,
+	#[cql(rename = "zip_code")]
+	zip: Option<i32>
+}
+
+fn row(columns: Vec<(&str, Column)>) -> Row {
+	let specs = columns.iter().map(|&(name, _)| ColumnSpec {
+		name: name.to_string(),
+		data_type: ColumnType::Custom,
+		collection_spec: CollectionSpec::None
+	}).collect();
+	Row {
+		specs: Arc::new(specs),
+		columns: columns.into_iter().map(|(_, column)| column).collect()
+	}
+}
+
+#[test]
+fn test_derive_from_row() {
+	let r = row(vec![
+		("age", Column::Int(42)),
+		("user_id", Column::String("jsmith".to_string())),
+		("height", Column::None)
+	]);
+	let user = User::from_row(&r).unwrap();
+	assert_eq!(user, User { id: "jsmith".to_string(), age: 42, height: None, tags: vec!() });
+
+	let r = row(vec![("user_id", Column::String("jsmith".to_string())), ("age", Column::String("42".to_string()))]);
+	assert_eq!(User::from_row(&r).unwrap_err(), MappingError::TypeMismatch {
+		column: Some("age".to_string()),
+		expected: "int",
+		found: "string"
+	});
+}
+
+#[test]
+fn test_derive_into_values() {
+	let user = User { id: "jsmith".to_string(), age: 42, height: Some(1.5), tags: vec!() };
+	let named = User { id: "jsmith".to_string(), age: 42, height: None, tags: vec!() }.into_named_values();
+	assert_eq!(named[0], ("user_id".to_string(), Column::String("jsmith".to_string())));
+	assert_eq!(user.into_values()[2], Column::Float(1.5));
+}
+
+#[test]
+fn test_derive_udt() {
+	let address = Address { street: "Main st".to_string(), zip: Some(12345) };
+	let column = address.to_cql();
+	assert_eq!(column, Column::Udt(vec![
+		("street".to_string(), Column::String("Main st".to_string())),
+		("zip_code".to_string(), Column::Int(12345))
+	]));
+	assert_eq!(Address::from_cql(&column).unwrap(), address);
+
+	let partial = Column::Udt(vec![("street".to_string(), Column::String("Main st".to_string()))]);
+	assert_eq!(Address::from_cql(&partial).unwrap().zip, None);
+}
@@ -0,0 +1,79 @@
+#![cfg(feature = "serde")]
+
+extern crate rustcql;
+#[macro_use]
+extern crate serde;
+extern crate serde_json;
+
+use std::sync::Arc;
+
+use rustcql::serialization::{
+	from_row,
+	to_named_values
+};
+
+use rustcql::shared::{
+	Column,
+	ColumnSpec,
+	ColumnType,
+	CollectionSpec,
+	Row
+};
+
+fn row(columns: Vec<(&str, Column)>) -> Row {
+	let specs = columns.iter().map(|&(name, _)| ColumnSpec {
+		name: name.to_string(),
+		data_type: ColumnType::Custom,
+		collection_spec: CollectionSpec::None
+	}).collect();
+	Row {
+		specs: Arc::new(specs),
+		columns: columns.into_iter().map(|(_, column)| column).collect()
+	}
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct User {
+	user_id: String,
+	age: i32,
+	height: Option<f32>,
+	emails: Vec<String>
+}
+
+#[test]
+fn test_row_to_json() {
+	let r = row(vec![
+		("id", Column::String("0b1e6d3c-3c5c-11e6-ac61-9e71128cae77".to_string())),
+		("avatar", Column::Blob(vec![1, 2, 3])),
+		("prefs", Column::Map(vec![(Column::String("theme".to_string()), Column::String("dark".to_string()))])),
+		("deleted", Column::None)
+	]);
+	assert_eq!(serde_json::to_string(&r).unwrap(),
+		r#"{"id":"0b1e6d3c-3c5c-11e6-ac61-9e71128cae77","avatar":"AQID","prefs":{"theme":"dark"},"deleted":null}"#);
+}
+
+#[test]
+fn test_row_deserialize() {
+	let r = row(vec![
+		("user_id", Column::String("jsmith".to_string())),
+		("age", Column::Int(42)),
+		("height", Column::None),
+		("emails", Column::Set(vec![Column::String("js@example.com".to_string())]))
+	]);
+	let user: User = from_row(&r).unwrap();
+	assert_eq!(user, User { user_id: "jsmith".to_string(), age: 42, height: None, emails: vec!["js@example.com".to_string()] });
+
+	let (user_id, age): (String, i32) = from_row(&row(vec![("user_id", Column::String("jsmith".to_string())), ("age", Column::Int(42))])).unwrap();
+	assert_eq!((user_id.as_str(), age), ("jsmith", 42));
+}
+
+#[test]
+fn test_named_values() {
+	let user = User { user_id: "jsmith".to_string(), age: 42, height: Some(1.5), emails: vec!() };
+	assert_eq!(to_named_values(&user).unwrap(), vec![
+		("user_id".to_string(), Column::String("jsmith".to_string())),
+		("age".to_string(), Column::Int(42)),
+		("height".to_string(), Column::Float(1.5)),
+		("emails".to_string(), Column::List(vec!()))
+	]);
+}